@@ -0,0 +1,192 @@
+use std::fmt;
+use std::time::Duration;
+use k8s_openapi::api::apps::v1::ReplicaSet;
+use k8s_openapi::api::core::v1::{ContainerState, Event, Pod};
+use k8s_openapi::chrono::{DateTime, Utc};
+use kube::api::ListParams;
+use kube::{Api, Client, ResourceExt};
+use serde::Serialize;
+
+/// Only that many pods are described (and have their events fetched), to keep the error small.
+const MAX_DIAGNOSED_PODS: usize = 3;
+/// Most recent events of all described objects together.
+const MAX_EVENTS: usize = 10;
+/// Diagnostics are best effort, a slow api server must not hold the error response for long.
+const EVENTS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Why pods of the application can't receive traffic, as `kubectl describe` would tell.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Diagnostics {
+    pub pods: Vec<PodDiagnostics>,
+    pub events: Vec<EventSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PodDiagnostics {
+    pub name: String,
+    pub phase: Option<String>,
+    pub terminating: bool,
+    /// containers which are not ready, with the reason (e.g. `CrashLoopBackOff`, `ImagePullBackOff`)
+    pub containers: Vec<ContainerDiagnostics>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerDiagnostics {
+    pub name: String,
+    pub state: &'static str,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub restarts: i32,
+    /// reason the previous instance of the container terminated with
+    pub last_termination: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EventSummary {
+    /// e.g. `Pod/my-app-7d4b9c-x2x8z` or `Deployment/my-app`
+    pub object: String,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+    pub count: Option<i32>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Describes given pods and fetches recent events of them and of their Deployment.
+/// Failures to fetch events are logged and otherwise ignored.
+pub async fn diagnose(client: &Client, namespace: &str, pods: &[&Pod]) -> Diagnostics {
+    let pods = &pods[..pods.len().min(MAX_DIAGNOSED_PODS)];
+    let described = pods.iter().map(|pod| describe_pod(pod)).collect();
+
+    let events = match tokio::time::timeout(EVENTS_TIMEOUT, recent_events(client, namespace, pods)).await {
+        Ok(events) => events,
+        Err(_) => {
+            log::warn!("fetching events in namespace {} timed out", namespace);
+            Vec::new()
+        }
+    };
+
+    Diagnostics { pods: described, events }
+}
+
+fn describe_pod(pod: &Pod) -> PodDiagnostics {
+    let status = pod.status.clone().unwrap_or_default();
+    let containers = status.init_container_statuses.iter().flatten()
+        .chain(status.container_statuses.iter().flatten())
+        .filter(|container| !container.ready)
+        .map(|container| {
+            let (state, reason, message) = match &container.state {
+                Some(ContainerState { waiting: Some(waiting), .. }) => ("waiting", waiting.reason.clone(), waiting.message.clone()),
+                Some(ContainerState { terminated: Some(terminated), .. }) => ("terminated", terminated.reason.clone(), terminated.message.clone()),
+                Some(ContainerState { running: Some(_), .. }) => ("running", None, None),
+                _ => ("unknown", None, None),
+            };
+            let last_termination = container.last_state.as_ref()
+                .and_then(|last_state| last_state.terminated.as_ref())
+                .map(|terminated| format!("{} (exit code {})", terminated.reason.as_deref().unwrap_or("Terminated"), terminated.exit_code));
+
+            ContainerDiagnostics { name: container.name.clone(), state, reason, message, restarts: container.restart_count, last_termination }
+        })
+        .collect();
+
+    PodDiagnostics {
+        name: pod.name_any(),
+        phase: status.phase,
+        terminating: pod.metadata.deletion_timestamp.is_some(),
+        containers,
+    }
+}
+
+async fn recent_events(client: &Client, namespace: &str, pods: &[&Pod]) -> Vec<EventSummary> {
+    let mut objects: Vec<(&str, String)> = pods.iter().map(|pod| ("Pod", pod.name_any())).collect();
+    if let Some(deployment) = owning_deployment(client, namespace, pods).await {
+        objects.push(("Deployment", deployment));
+    }
+
+    let events: Api<Event> = Api::namespaced(client.clone(), namespace);
+    let mut found = Vec::new();
+    for (kind, name) in objects {
+        let params = ListParams::default().fields(&format!("involvedObject.kind={},involvedObject.name={}", kind, name));
+        match events.list(&params).await {
+            Ok(list) => found.extend(list.items.into_iter().map(|event| summarize(kind, &name, event))),
+            Err(err) => log::warn!("unable to list events of {}/{} in namespace {}: {}", kind, name, namespace, err),
+        }
+    }
+
+    // most recent last, as kubectl shows them
+    found.sort_by_key(|event| event.last_seen);
+    let skipped = found.len().saturating_sub(MAX_EVENTS);
+    found.split_off(skipped)
+}
+
+/// Deployment which owns the pods through a ReplicaSet, if there is one.
+async fn owning_deployment(client: &Client, namespace: &str, pods: &[&Pod]) -> Option<String> {
+    let replica_set = pods.iter()
+        .flat_map(|pod| pod.owner_references())
+        .find(|owner| owner.kind == "ReplicaSet")?;
+
+    let replica_sets: Api<ReplicaSet> = Api::namespaced(client.clone(), namespace);
+    let replica_set = match replica_sets.get(&replica_set.name).await {
+        Ok(replica_set) => replica_set,
+        Err(err) => {
+            log::warn!("unable to get replica set {} in namespace {}: {}", replica_set.name, namespace, err);
+            return None;
+        }
+    };
+
+    replica_set.owner_references().iter()
+        .find(|owner| owner.kind == "Deployment")
+        .map(|owner| owner.name.clone())
+}
+
+fn summarize(kind: &str, name: &str, event: Event) -> EventSummary {
+    let last_seen = event.last_timestamp.map(|time| time.0)
+        .or(event.event_time.map(|time| time.0))
+        .or(event.first_timestamp.map(|time| time.0));
+
+    EventSummary {
+        object: format!("{}/{}", kind, name),
+        type_: event.type_,
+        reason: event.reason,
+        message: event.message,
+        count: event.count,
+        last_seen,
+    }
+}
+
+/// Single line, meant for logs.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pods = self.pods.iter().map(|pod| {
+            let mut described = format!("pod {} is {}", pod.name, pod.phase.as_deref().unwrap_or("Unknown"));
+            if pod.terminating {
+                described.push_str(" (terminating)");
+            }
+            for container in &pod.containers {
+                described.push_str(&format!(", container {} {}", container.name, container.state));
+                if let Some(reason) = &container.reason {
+                    described.push_str(&format!(" {}", reason));
+                }
+                if container.restarts > 0 {
+                    described.push_str(&format!(" after {} restarts", container.restarts));
+                }
+                if let Some(last_termination) = &container.last_termination {
+                    described.push_str(&format!(", last terminated with {}", last_termination));
+                }
+            }
+            described
+        });
+        let events = self.events.iter().map(|event| {
+            format!("{} {} {}{}: {}",
+                    event.object,
+                    event.type_.as_deref().unwrap_or("Normal"),
+                    event.reason.as_deref().unwrap_or_default(),
+                    event.count.filter(|count| *count > 1).map(|count| format!(" (x{})", count)).unwrap_or_default(),
+                    event.message.as_deref().unwrap_or_default())
+        });
+
+        let described: Vec<String> = pods.chain(events).collect();
+        f.write_str(&described.join("; "))
+    }
+}
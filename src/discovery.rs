@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::sync::Arc;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::{Pod, Service as KubeService};
+use kube::api::ListParams;
+use kube::runtime::reflector::{self, ObjectRef, Store};
+use kube::runtime::{watcher, WatchStreamExt};
+use kube::{Api, Client, Resource, ResourceExt};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tokio::sync::watch;
+
+use crate::forward_error::is_forbidden;
+
+#[derive(Debug, Error)]
+pub enum DiscoveryError {
+    #[error("watch of {kind} in namespace {namespace} has stopped")]
+    Stopped { kind: &'static str, namespace: String },
+    #[error("not allowed to watch {kind} in namespace {namespace}: {message}")]
+    Forbidden { kind: &'static str, namespace: String, message: String },
+}
+
+/// In-memory view of pods and services, kept up to date by watches (one per namespace
+/// and resource kind). Namespaces are watched starting from the first request targeting them.
+pub struct Discovery {
+    client: Client,
+    namespaces: Mutex<HashMap<String, NamespaceCache>>,
+}
+
+#[derive(Clone)]
+struct NamespaceCache {
+    pods: Cache<Pod>,
+    services: Cache<KubeService>,
+}
+
+/// Reflector's store together with the state of its watch.
+#[derive(Clone)]
+struct Cache<K: 'static + Resource<DynamicType = ()>> {
+    store: Store<K>,
+    state: watch::Receiver<WatchState>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct WatchState {
+    /// number of watch events applied to the store, 0 until initial list is received
+    generation: u64,
+    /// set while the api server refuses the watch (RBAC), cleared once it succeeds
+    forbidden: Option<String>,
+}
+
+impl Discovery {
+    pub fn new(client: Client) -> Discovery {
+        Discovery { client, namespaces: Mutex::new(HashMap::new()) }
+    }
+
+    pub async fn get_service(&self, namespace: &str, name: &str) -> Result<Option<Arc<KubeService>>, DiscoveryError> {
+        let services = self.namespace(namespace).services;
+        services.synced("services", namespace).await?;
+        Ok(services.store.get(&ObjectRef::new(name).within(namespace)))
+    }
+
+    /// Returns pods which labels match all entries of the given selector.
+    pub async fn find_pods(&self, namespace: &str, selector: &BTreeMap<String, String>) -> Result<Vec<Arc<Pod>>, DiscoveryError> {
+        let pods = self.namespace(namespace).pods;
+        pods.synced("pods", namespace).await?;
+
+        let found = pods.store.state().into_iter()
+            .filter(|pod| {
+                let labels = pod.labels();
+                selector.iter().all(|(key, value)| labels.get(key) == Some(value))
+            })
+            .collect();
+
+        Ok(found)
+    }
+
+    /// Waits until any pod matching the selector is `ready`, then returns all matching pods.
+    /// Callers bound the wait with a timeout.
+    pub async fn wait_for_pods<F>(&self, namespace: &str, selector: &BTreeMap<String, String>, ready: F) -> Result<Vec<Arc<Pod>>, DiscoveryError>
+    where
+        F: Fn(&Pod) -> bool,
+    {
+        let mut state = self.namespace(namespace).pods.state;
+        loop {
+            // every change applied after that is noticed by `changed`
+            state.borrow_and_update();
+            let found = self.find_pods(namespace, selector).await?;
+            if found.iter().any(|pod| ready(pod)) {
+                return Ok(found);
+            }
+
+            if state.changed().await.is_err() {
+                return Err(DiscoveryError::Stopped { kind: "pods", namespace: String::from(namespace) });
+            }
+        }
+    }
+
+    fn namespace(&self, namespace: &str) -> NamespaceCache {
+        let mut namespaces = self.namespaces.lock();
+        namespaces.entry(String::from(namespace))
+            .or_insert_with(|| {
+                log::info!("starting to watch pods and services in namespace {}", namespace);
+                NamespaceCache {
+                    pods: Cache::spawn(Api::namespaced(self.client.clone(), namespace)),
+                    services: Cache::spawn(Api::namespaced(self.client.clone(), namespace)),
+                }
+            })
+            .clone()
+    }
+}
+
+impl<K> Cache<K>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+{
+    fn spawn(api: Api<K>) -> Cache<K> {
+        let (store, writer) = reflector::store();
+        let (state_tx, state) = watch::channel(WatchState::default());
+
+        let events = reflector::reflector(writer, watcher(api, ListParams::default()))
+            .backoff(watcher::default_backoff());
+
+        tokio::spawn(async move {
+            let mut events = events.boxed();
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(_) => state_tx.send_modify(|state| {
+                        state.generation += 1;
+                        state.forbidden = None;
+                    }),
+                    Err(err) => {
+                        log::warn!("watch of {} failed: {}", K::kind(&()), err);
+                        if let Some(message) = forbidden_message(&err) {
+                            state_tx.send_modify(|state| state.forbidden = Some(message));
+                        }
+                    }
+                }
+            }
+        });
+
+        Cache { store, state }
+    }
+}
+
+impl<K: 'static + Resource<DynamicType = ()>> Cache<K> {
+    /// Waits for the initial list, callers bound it with the discovery timeout. Fails right
+    /// away when the api server doesn't allow the watch, instead of waiting for the timeout.
+    async fn synced(&self, kind: &'static str, namespace: &str) -> Result<(), DiscoveryError> {
+        let mut state = self.state.clone();
+        let synced = state.wait_for(|state| state.generation > 0 || state.forbidden.is_some()).await
+            .map(|state| state.clone());
+        match synced {
+            Ok(WatchState { generation: 0, forbidden: Some(message) }) => {
+                Err(DiscoveryError::Forbidden { kind, namespace: String::from(namespace), message })
+            }
+            Ok(_) => Ok(()),
+            Err(_) => Err(DiscoveryError::Stopped { kind, namespace: String::from(namespace) }),
+        }
+    }
+}
+
+fn forbidden_message(err: &watcher::Error) -> Option<String> {
+    match err {
+        watcher::Error::InitialListFailed(err)
+        | watcher::Error::WatchStartFailed(err)
+        | watcher::Error::WatchFailed(err) if is_forbidden(err) => Some(err.to_string()),
+        watcher::Error::WatchError(response) if response.code == 403 => Some(response.message.clone()),
+        _ => None,
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use hyper::{Response, StatusCode};
+use k8s_openapi::api::core::v1::Pod;
+use kube::error::ErrorResponse;
+use kube::ResourceExt;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::diagnostics::Diagnostics;
+use crate::discovery::DiscoveryError;
+use crate::port_forward::PortForwardError;
+use crate::target_host::InvalidHost;
+use crate::timeouts::{Phase, TimedOut};
+
+/// Everything which can go wrong while forwarding a request, each variant is returned
+/// to the client with its own status code.
+#[derive(Debug, Error)]
+pub enum ForwardError {
+    #[error(transparent)]
+    InvalidHost(#[from] InvalidHost),
+    #[error(transparent)]
+    Discovery(#[from] DiscoveryError),
+    #[error("no service or pods found for {application_name} in namespace {namespace}")]
+    NoSuchService { application_name: String, namespace: String },
+    #[error("service {application_name} in namespace {namespace} has no pod selector")]
+    NoSelector { application_name: String, namespace: String },
+    #[error("service {application_name} in namespace {namespace} has no {}", port.map_or(String::from("ports"), |port| format!("port {}", port)))]
+    NoServicePort { application_name: String, namespace: String, port: Option<u16> },
+    #[error("no ready pods for {application_name} in namespace {namespace} - {} pods found, but all are unready or terminating", pods.len())]
+    NoReadyPods { application_name: String, namespace: String, pods: Vec<Arc<Pod>> },
+    #[error("no healthy pods for {application_name} in namespace {namespace} - all {ready} ready pods fail health check")]
+    NoHealthyPods { application_name: String, namespace: String, ready: usize },
+    #[error("unable to find container port {port} in pod {pod}")]
+    NoContainerPort { pod: String, port: String },
+    #[error("port-forward to pod {} failed: {source}", pod.name_any())]
+    PortForward { pod: Arc<Pod>, source: Box<PortForwardError> },
+    #[error("unable to establish http connection to pod: {0}")]
+    Handshake(#[source] hyper::Error),
+    #[error("pod sent malformed response: {0}")]
+    Protocol(#[source] hyper::Error),
+    #[error("connection to pod failed: {0}")]
+    Upstream(#[source] hyper::Error),
+    #[error(transparent)]
+    TimedOut(#[from] TimedOut),
+    #[error("{host} is not a service of the cluster, proxying to other hosts is not allowed")]
+    ExternalHost { host: String },
+    #[error("unable to connect to {host}: {source}")]
+    ExternalConnect { host: String, source: std::io::Error },
+    #[error("request to {host} failed: {source}")]
+    External { host: String, source: hyper::Error },
+}
+
+impl ForwardError {
+    /// Error returned by `send_request`, malformed responses are told apart from broken connections.
+    pub fn from_upstream(err: hyper::Error) -> ForwardError {
+        if err.is_parse() || err.is_parse_status() || err.is_parse_too_large() {
+            ForwardError::Protocol(err)
+        } else {
+            ForwardError::Upstream(err)
+        }
+    }
+
+    /// Request failed before anything was sent to the pod (discovery, port-forward...),
+    /// so it can be retried whatever its method is.
+    pub fn is_not_sent(&self) -> bool {
+        match self {
+            ForwardError::InvalidHost(_)
+            | ForwardError::Discovery(_)
+            | ForwardError::NoSuchService { .. }
+            | ForwardError::NoSelector { .. }
+            | ForwardError::NoServicePort { .. }
+            | ForwardError::NoReadyPods { .. }
+            | ForwardError::NoHealthyPods { .. }
+            | ForwardError::NoContainerPort { .. }
+            | ForwardError::PortForward { .. }
+            | ForwardError::Handshake(_)
+            | ForwardError::ExternalHost { .. }
+            | ForwardError::ExternalConnect { .. } => true,
+            // request was refused by the connection (e.g. it was closed meanwhile) before it was written
+            ForwardError::Upstream(err) => err.is_canceled(),
+            ForwardError::Protocol(_) | ForwardError::External { .. } => false,
+            ForwardError::TimedOut(timed_out) => matches!(timed_out.phase, Phase::Discovery | Phase::PortForward),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ForwardError::InvalidHost(_) => StatusCode::BAD_REQUEST,
+            ForwardError::Discovery(DiscoveryError::Forbidden { .. }) => StatusCode::FORBIDDEN,
+            ForwardError::Discovery(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ForwardError::NoSuchService { .. } | ForwardError::NoServicePort { .. } => StatusCode::NOT_FOUND,
+            ForwardError::ExternalHost { .. } => StatusCode::FORBIDDEN,
+            ForwardError::NoReadyPods { .. } | ForwardError::NoHealthyPods { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ForwardError::PortForward { source, .. } if is_port_forward_forbidden(source) => StatusCode::FORBIDDEN,
+            ForwardError::NoSelector { .. }
+            | ForwardError::NoContainerPort { .. }
+            | ForwardError::PortForward { .. }
+            | ForwardError::Handshake(_)
+            | ForwardError::Protocol(_)
+            | ForwardError::Upstream(_)
+            | ForwardError::ExternalConnect { .. }
+            | ForwardError::External { .. } => StatusCode::BAD_GATEWAY,
+            ForwardError::TimedOut(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Short, stable identifier of the failure, meant for machines reading the error body.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ForwardError::InvalidHost(_) => "invalid_host",
+            ForwardError::Discovery(DiscoveryError::Forbidden { .. }) => "discovery_forbidden",
+            ForwardError::Discovery(_) => "discovery_failed",
+            ForwardError::NoSuchService { .. } => "no_such_service",
+            ForwardError::NoSelector { .. } => "no_pod_selector",
+            ForwardError::NoServicePort { .. } => "no_such_port",
+            ForwardError::NoReadyPods { .. } => "no_ready_pods",
+            ForwardError::NoHealthyPods { .. } => "no_healthy_pods",
+            ForwardError::NoContainerPort { .. } => "no_container_port",
+            ForwardError::PortForward { source, .. } if is_port_forward_forbidden(source) => "port_forward_forbidden",
+            ForwardError::PortForward { .. } => "port_forward_failed",
+            ForwardError::Handshake(_) => "upstream_handshake_failed",
+            ForwardError::Protocol(_) => "upstream_protocol_error",
+            ForwardError::Upstream(_) => "upstream_io_error",
+            ForwardError::TimedOut(_) => "timeout",
+            ForwardError::ExternalHost { .. } => "external_host_rejected",
+            ForwardError::ExternalConnect { .. } => "external_connect_failed",
+            ForwardError::External { .. } => "external_request_failed",
+        }
+    }
+
+    /// Pods (with their namespace) whose state explains the failure. They are diagnosed only when
+    /// the error is returned to the client, not on every failed attempt.
+    pub fn diagnosed_pods(&self) -> Option<(String, Vec<&Pod>)> {
+        match self {
+            ForwardError::NoReadyPods { namespace, pods, .. } => Some((namespace.clone(), pods.iter().map(Arc::as_ref).collect())),
+            ForwardError::PortForward { pod, .. } => Some((pod.namespace().unwrap_or_default(), vec![pod.as_ref()])),
+            _ => None,
+        }
+    }
+
+    /// `{"status": 503, "error": "no_ready_pods", "message": "...", "diagnostics": {"pods": [...], "events": [...]}}`
+    pub fn to_response(&self, diagnostics: Option<&Diagnostics>) -> Response<hyper::Body> {
+        let status = self.status();
+        let mut body = json!({
+            "status": status.as_u16(),
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+        if let Some(diagnostics) = diagnostics {
+            body["diagnostics"] = json!(diagnostics);
+        }
+
+        Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(format!("{}\n", body).into())
+            .unwrap()
+    }
+}
+
+fn is_port_forward_forbidden(err: &PortForwardError) -> bool {
+    matches!(err, PortForwardError::Open(err) if is_forbidden(err))
+}
+
+/// Api server refused the call because of RBAC.
+pub fn is_forbidden(err: &kube::Error) -> bool {
+    matches!(err, kube::Error::Api(ErrorResponse { code: 403, .. }))
+}
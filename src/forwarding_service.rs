@@ -1,44 +1,46 @@
 use std::error::Error;
-use std::fmt;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 use std::convert::Infallible;
 use std::task::{Context, Poll};
-use futures::future::BoxFuture;
-use hyper::client::conn::{Builder, SendRequest};
-use hyper::{Request, Response};
+use futures::future::{join_all, select, BoxFuture, Either};
+use hyper::client::conn::Builder;
+use hyper::upgrade::OnUpgrade;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, HOST};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode, Version};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use clap::ValueEnum;
+use rand::Rng;
 use k8s_openapi::api::core::v1::Pod;
-use kube::api::ListParams;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
 use kube::{Api, Client, ResourceExt};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::time::sleep;
+use tokio::io::copy_bidirectional;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tower::retry::budget::Budget;
 use tower::Service;
-use futures::StreamExt;
+use http_body::Body;
 use std::fmt::Debug;
 use tower::Layer;
 
-use crate::reply_body::ReplayBody;
-const MAX_RETRIES: usize = 10;
-
-#[derive(Debug, Clone)]
-struct RuntimeError {
-    cause: String
-}
-
-impl Error for RuntimeError {}
-
-impl RuntimeError {
-    fn from(msg: &str) -> RuntimeError {
-        let cause = String::from(msg);
-        RuntimeError {cause}
-    }
-}
-
-impl fmt::Display for RuntimeError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Port-forwarder failed in runtime: cause {}", self.cause)
-    }
-}
+use crate::diagnostics::diagnose;
+use crate::discovery::Discovery;
+use crate::port_forward::{PortForwardSessions, PortStream};
+use crate::reply_body::{ReplayBody, SpillConfig};
+use crate::forward_error::ForwardError;
+use crate::health_check::{self, HealthCheckConfig, HealthProbe, HealthStatus};
+use crate::hedging::{HedgePolicy, Latencies};
+use crate::outlier::{OutlierDetector, OutlierPolicy};
+use crate::proxy::{is_proxy_request, pass_through, strip_proxy_headers, ExternalHosts};
+use crate::retry::{RetryBudgets, RetryPolicy};
+use crate::target_host::TargetHost;
+use crate::timeouts::{with_timeout, Phase, Timeouts};
+use crate::websocket::frame_logger;
+use crate::upgrade::{is_upgrade, splice, UpgradeTap};
+use crate::upstream_body::UpstreamBody;
+use crate::upstream_pool::{MultiplexedConnection, PoolConfig, PoolKey, PooledConnection, UpstreamPool};
+const DEFAULT_PORT: u16 = 8080;
 
 #[derive(Debug, Clone)]
 pub struct LogLayer;
@@ -72,29 +74,20 @@ where
 
     fn call(&mut self, req: Request<hyper::Body>) -> Self::Future {
         println!("Service called with {req:?}");
-        let headers = req.headers().clone();
-        let method = req.method().clone();
-        let uri: hyper::Uri = req.uri().clone();
+        // parts keep version and extensions (downstream upgrade lives there)
+        let (mut parts, body) = req.into_parts();
         
-        let host = String::from(headers.get("host").unwrap().to_str().unwrap());
-        let debugable_body = req.into_body()
-            .map(move |chunk| {
-                if let Ok(data) = &chunk {
-                    let string = std::str::from_utf8(data).unwrap();
-                    log::info!("[{host}] request body chunk {}", string);
-                }
-                chunk
-            });
-
-        let debugable_body = hyper::Body::wrap_stream(debugable_body);
-        
-        let mut request = Request::builder()
-            .uri(uri)
-            .method(method)
-            .body(debugable_body)
-            .unwrap();
+        let host = request_host(&parts.headers, &parts.uri).unwrap_or_default();
+        if is_upgrade(&parts.headers) && log::log_enabled!(log::Level::Debug) {
+            parts.extensions.insert(frame_logger(host.clone()));
+        }
+        let debugable_body = debugable(body, move |data| {
+            if let Ok(printable) = std::str::from_utf8(data) {
+                log::info!("[{host}] request body chunk {}", printable);
+            }
+        });
 
-        request.headers_mut().extend(headers);
+        let request = Request::from_parts(parts, debugable_body);
     
         let res = self
             .inner
@@ -105,19 +98,12 @@ where
 
             let (parts, body) = response.into_parts();
             
-            let debugable_body = body
-                .map(move |chunk| {
-                    if let Ok(data) = &chunk {
-                        let maybe_printable = std::str::from_utf8(data);
-                        match maybe_printable {
-                            Ok(printable) => {log::info!("resposne body chunk {}", printable)}
-                            Err(_) => {}
-                        }
-                    }
-                    chunk
-                });
-        
-            let debugable_body = hyper::Body::wrap_stream(debugable_body);
+            let debugable_body = debugable(body, |data| {
+                let maybe_printable = std::str::from_utf8(data);
+                if let Ok(printable) = maybe_printable {
+                    log::info!("resposne body chunk {}", printable)
+                }
+            });
             let response = Response::from_parts(parts, debugable_body);
 
             Ok(response)
@@ -126,16 +112,322 @@ where
     }
 }
 
+/// Passes chunks of the body to `log_chunk` as they go through. Trailers are kept, gRPC sends its status in them.
+fn debugable<F>(mut body: hyper::Body, log_chunk: F) -> hyper::Body
+where
+    F: Fn(&[u8]) + Send + 'static,
+{
+    // empty bodies stay empty, so they are still sent without chunked encoding
+    if body.is_end_stream() {
+        return body;
+    }
+
+    let (mut sender, debugable_body) = hyper::Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(data) => {
+                    log_chunk(&data);
+                    if sender.send_data(data).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    log::info!("body failed: {}", err);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                let _ = sender.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(_) => sender.abort(),
+        }
+    });
+    debugable_body
+}
+
+/// `Host` header of HTTP/1 request, or authority of HTTP/2 one.
+fn request_host(headers: &HeaderMap, uri: &hyper::Uri) -> Option<String> {
+    // authority of absolute-form (proxy requests) and HTTP/2 uri wins over the Host header (RFC 9112, 3.2.3)
+    uri.authority().map(|authority| authority.as_str())
+        .or_else(|| headers.get(hyper::header::HOST).and_then(|host| host.to_str().ok()))
+        .map(String::from)
+}
+
+/// Settings shared by all forwarded requests.
+#[derive(Debug, Clone)]
+pub struct ForwardingConfig {
+    /// url of the cluster api server, identifies cluster in the upstream pool
+    pub cluster: String,
+    pub cluster_domain: String,
+    /// namespace used for hosts without one (`http://my-app/`)
+    pub default_namespace: String,
+    pub balancing: BalancingStrategy,
+    pub pool: PoolConfig,
+    pub retry: RetryPolicy,
+    /// request bodies up to that size are buffered, so they can be retried
+    pub max_replay_body_bytes: usize,
+    /// when set, bodies larger than `max_replay_body_bytes` are buffered on disk
+    pub spill: Option<SpillConfig>,
+    pub timeouts: Timeouts,
+    /// when set, slow GETs are sent to a second pod as well
+    pub hedge: Option<HedgePolicy>,
+    /// when set, requests to a target without ready pods wait that long for one to become ready
+    pub wait_for_ready: Option<Duration>,
+    pub outlier: OutlierPolicy,
+    /// when set, pods of configured targets are probed and the failing ones are not used
+    pub health_check: Option<HealthCheckConfig>,
+    /// targets spoken to over HTTP/2, besides those with HTTP/2 `appProtocol` of the Service port
+    pub http2_targets: HashSet<TargetHost>,
+    /// what happens to proxy requests (absolute-form or CONNECT) for hosts outside of the cluster
+    pub external_hosts: ExternalHosts,
+}
+
+/// State shared by all downstream connections.
+pub struct Forwarder {
+    client: Client,
+    config: ForwardingConfig,
+    discovery: Discovery,
+    balancer: Box<dyn Balancer>,
+    outstanding: Arc<OutstandingRequests>,
+    pool: Arc<UpstreamPool>,
+    sessions: Arc<PortForwardSessions>,
+    budgets: RetryBudgets,
+    latencies: Latencies,
+    outliers: OutlierDetector,
+    health: HealthStatus,
+}
+
+impl Forwarder {
+    pub fn new(client: Client, config: ForwardingConfig) -> Arc<Forwarder> {
+        let discovery = Discovery::new(client.clone());
+        let outstanding = Arc::new(OutstandingRequests::default());
+        let balancer: Box<dyn Balancer> = match config.balancing {
+            BalancingStrategy::RoundRobin => Box::new(RoundRobin::default()),
+            BalancingStrategy::Random => Box::new(RandomChoice),
+            BalancingStrategy::LeastRequests => Box::new(LeastRequests { outstanding: outstanding.clone() }),
+        };
+        let pool = UpstreamPool::new(config.pool.clone());
+        let sessions = PortForwardSessions::new();
+        let budgets = RetryBudgets::new(&config.retry);
+        let latencies = Latencies::new();
+        let outliers = OutlierDetector::new(config.outlier.clone());
+        let health = HealthStatus::default();
+        let forwarder = Arc::new(Forwarder { client, config, discovery, balancer, outstanding, pool, sessions, budgets, latencies, outliers, health });
+        if let Some(health_check) = &forwarder.config.health_check {
+            tokio::spawn(check_health(Arc::downgrade(&forwarder), health_check.interval));
+        }
+        forwarder
+    }
+}
+
+impl Forwarder {
+    /// Copies bytes between the client and a pod of the target in both directions, until both are done.
+    pub async fn forward_tcp(&self, target: &TargetHost, mut client: TcpStream, peer: SocketAddr) {
+        let host = format!("{}.{}:{}", target.application_name, target.namespace, target.port.unwrap_or_default());
+        let (mut stream, pod) = match self.open_stream(target, &host).await {
+            Ok(opened) => opened,
+            Err(err) => {
+                log::error!("[{}] unable to forward connection from {}: {}", host, peer, err);
+                return;
+            }
+        };
+
+        log::info!("[{}] forwarding connection from {} to pod {}", host, peer, pod);
+        let _in_flight = self.outstanding.start(&pod);
+        match copy_bidirectional(&mut client, &mut stream).await {
+            Ok((sent, received)) => log::info!("[{}] connection from {} closed, {} bytes sent, {} bytes received", host, peer, sent, received),
+            Err(err) => log::info!("[{}] connection from {} failed: {}", host, peer, err),
+        }
+    }
+}
+
+impl Forwarder {
+    /// Port-forwarded stream to a pod of the target, for connections which are not HTTP.
+    async fn open_stream(&self, target: &TargetHost, host: &str) -> Result<(Box<dyn PortStream>, String), ForwardError> {
+        let (stream, pod, _) = with_timeout(Phase::Attempt, self.config.timeouts.attempt, get_stream(self, target, host, None)).await??;
+        Ok((stream, pod))
+    }
+}
+
+/// Probes ready pods of targets with configured health check, until the forwarder is gone.
+async fn check_health(forwarder: Weak<Forwarder>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let forwarder = match forwarder.upgrade() {
+            Some(forwarder) => forwarder,
+            None => return,
+        };
+
+        if let Some(health_check) = &forwarder.config.health_check {
+            let targets = health_check.probes.iter()
+                .map(|(target, probe)| check_target_health(&forwarder, target, probe, health_check.timeout));
+            join_all(targets).await;
+        }
+    }
+}
+
+async fn check_target_health(forwarder: &Forwarder, target: &TargetHost, probe: &HealthProbe, probe_timeout: Duration) {
+    let host = format!("{}.{}", target.application_name, target.namespace);
+    let discovered = timeout(forwarder.config.timeouts.discovery, async {
+        let backend = get_backend(forwarder, target, &host).await?;
+        let found_pods = forwarder.discovery.find_pods(&target.namespace, &backend.selector).await?;
+        Ok::<_, ForwardError>((backend, found_pods))
+    });
+    let (backend, found_pods) = match discovered.await {
+        Ok(Ok(discovered)) => discovered,
+        Ok(Err(err)) => {
+            log::warn!("[{}] unable to find pods to health check: {}", host, err);
+            return;
+        }
+        Err(_) => {
+            log::warn!("[{}] finding pods to health check timed out", host);
+            return;
+        }
+    };
+
+    let probes = found_pods.iter()
+        .filter(|pod| is_pod_ready(pod))
+        .map(|pod| {
+            let (backend, host) = (&backend, &host);
+            async move {
+                match timeout(probe_timeout, probe_pod(forwarder, pod, backend, host, probe)).await {
+                    Ok(Ok(())) => None,
+                    Ok(Err(err)) => {
+                        log::warn!("[{}] pod {} failed health check: {}", host, pod.name_any(), err);
+                        Some(pod_key(pod))
+                    }
+                    Err(_) => {
+                        log::warn!("[{}] health check of pod {} timed out", host, pod.name_any());
+                        Some(pod_key(pod))
+                    }
+                }
+            }
+        });
+
+    let unhealthy = join_all(probes).await.into_iter().flatten().collect();
+    forwarder.health.update(target, unhealthy);
+}
+
+/// Probes the pod over port-forward, the same way requests get to it.
+async fn probe_pod(forwarder: &Forwarder, pod: &Pod, backend: &Backend, host: &str, probe: &HealthProbe) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let port = get_container_port(pod, &backend.target_port)?;
+    let pods: Api<Pod> = Api::namespaced(forwarder.client.clone(), &pod.namespace().unwrap_or_default());
+    let stream = forwarder.sessions.stream(&pods, pod, port).await?;
+    health_check::probe(stream, host, probe, backend.protocol == UpstreamProtocol::Http2).await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum BalancingStrategy {
+    RoundRobin,
+    Random,
+    /// power of two random choices, the one with less outstanding requests wins
+    LeastRequests,
+}
+
+/// Chooses pod (out of ready ones) a new upstream connection goes to.
+pub trait Balancer: Send + Sync {
+    /// Returns index of the chosen pod, `pods` is never empty.
+    fn pick(&self, target: &TargetHost, pods: &[&Pod]) -> usize;
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: Mutex<HashMap<TargetHost, usize>>,
+}
+
+impl Balancer for RoundRobin {
+    fn pick(&self, target: &TargetHost, pods: &[&Pod]) -> usize {
+        let mut next = self.next.lock().unwrap();
+        let next = next.entry(target.clone()).or_default();
+        let picked = *next % pods.len();
+        *next = next.wrapping_add(1);
+        picked
+    }
+}
+
+pub struct RandomChoice;
+
+impl Balancer for RandomChoice {
+    fn pick(&self, _target: &TargetHost, pods: &[&Pod]) -> usize {
+        rand::thread_rng().gen_range(0..pods.len())
+    }
+}
+
+pub struct LeastRequests {
+    outstanding: Arc<OutstandingRequests>,
+}
+
+impl Balancer for LeastRequests {
+    fn pick(&self, _target: &TargetHost, pods: &[&Pod]) -> usize {
+        if pods.len() == 1 {
+            return 0;
+        }
+
+        let chosen = rand::seq::index::sample(&mut rand::thread_rng(), pods.len(), 2);
+        let (first, second) = (chosen.index(0), chosen.index(1));
+        if self.outstanding.get(&pod_key(pods[second])) < self.outstanding.get(&pod_key(pods[first])) {
+            second
+        } else {
+            first
+        }
+    }
+}
+
+/// Number of in-flight requests per pod.
+#[derive(Default)]
+pub struct OutstandingRequests {
+    counters: Mutex<HashMap<String, usize>>,
+}
+
+impl OutstandingRequests {
+    fn get(&self, pod: &str) -> usize {
+        self.counters.lock().unwrap().get(pod).copied().unwrap_or(0)
+    }
+
+    /// Counts request as outstanding until returned guard is dropped.
+    fn start(self: &Arc<Self>, pod: &str) -> InFlight {
+        *self.counters.lock().unwrap().entry(String::from(pod)).or_default() += 1;
+        InFlight { outstanding: self.clone(), pod: String::from(pod) }
+    }
+}
+
+struct InFlight {
+    outstanding: Arc<OutstandingRequests>,
+    pod: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut counters = self.outstanding.counters.lock().unwrap();
+        if let Some(counter) = counters.get_mut(&self.pod) {
+            *counter -= 1;
+            if *counter == 0 {
+                counters.remove(&self.pod);
+            }
+        }
+    }
+}
+
+fn pod_key(pod: &Pod) -> String {
+    format!("{}/{}", pod.namespace().unwrap_or_default(), pod.name_any())
+}
+
 #[derive(Clone)]
 pub struct RequestHandlingService {
-    client: Client,
-    upstream_connection: Arc<Mutex<Option<SendRequest<ReplayBody<hyper::Body>>>>>
+    forwarder: Arc<Forwarder>,
 }
 
 impl RequestHandlingService {
-    pub fn new(client: Client) -> RequestHandlingService {
-        let empty = Arc::new(Mutex::new(None));
-        RequestHandlingService{ client: client, upstream_connection: empty }
+    pub fn new(forwarder: Arc<Forwarder>) -> RequestHandlingService {
+        RequestHandlingService{ forwarder }
     }
 }
 
@@ -148,116 +440,453 @@ impl Service<Request<hyper::Body>> for RequestHandlingService {
         Ok(()).into()
     }
 
-    fn call(&mut self, req: Request<hyper::Body>) -> Self::Future {
-        let client = self.client.clone();
-        let upstream_connection = self.upstream_connection.clone();
+    fn call(&mut self, mut req: Request<hyper::Body>) -> Self::Future {
+        let forwarder = self.forwarder.clone();
 
         let future = async move { 
 
+            if req.method() == Method::CONNECT {
+                return Ok(connect(forwarder, req).await);
+            }
+
+            let host = request_host(req.headers(), req.uri()).unwrap_or_default();
+            if is_proxy_request(&req) {
+                if !TargetHost::is_cluster_host(&host, &forwarder.config.cluster_domain) {
+                    return Ok(match forwarder.config.external_hosts {
+                        ExternalHosts::Reject => error_response(&forwarder, &host, ForwardError::ExternalHost { host: host.clone() }).await,
+                        ExternalHosts::PassThrough => {
+                            let passed = with_timeout(Phase::Request, forwarder.config.timeouts.request, pass_through(req)).await;
+                            match passed.unwrap_or_else(|timed_out| Err(timed_out.into())) {
+                                Ok(response) => response,
+                                Err(err) => error_response(&forwarder, &host, err).await,
+                            }
+                        }
+                    });
+                }
+                strip_proxy_headers(req.headers_mut(), &host);
+            }
+
+            // client side of the upgrade is ready once our 101 response is sent
+            let upgrade = is_upgrade(req.headers())
+                .then(|| (hyper::upgrade::on(&mut req), req.extensions().get::<UpgradeTap>().cloned()));
             let headers = req.headers().clone();
             let method = req.method().clone();
             let uri: hyper::Uri = req.uri().to_string().parse().unwrap();
-            let host = String::from(headers.get("host").unwrap().to_str().unwrap());
 
             let body: hyper::Body = req.into_body();
 
-            let mut retries: usize = 0;
-            let upstream_connection = upstream_connection;
-            let replay_body = ReplayBody::try_new(body, 1024).expect("channel body must not be too large");
-            let cloned_reply = replay_body.clone();
-
-            let mut request = Request::builder()
-                    .uri(uri.clone())
-                    .method(method.to_string().as_str())
-                    .body(replay_body)
-                    .unwrap();
-                
-            request.headers_mut().extend(headers.clone());
-
-            //initial request - because of original request body needs to be read (probably?)
-            match perform_forward(client.clone(), request, upstream_connection.clone()).await {
-                Ok(response) => {
-                    return Ok(response)
-                }, 
+            let target = match TargetHost::parse(&host, &forwarder.config.cluster_domain, &forwarder.config.default_namespace) {
+                Ok(target) => target,
                 Err(err) => {
-                    log::error!("Forwarding failed ! retrry will be performed {}: {}",host, err);
+                    log::error!("[{}] {}", host, err);
+                    return Ok(ForwardError::from(err).to_response(None));
                 }
+            };
+
+            let timeouts = &forwarder.config.timeouts;
+            let forwarded = with_timeout(Phase::Request, timeouts.request, forward_with_retries(&forwarder, &target, &host, method, uri, headers, body)).await;
+            let forwarded = match forwarded {
+                Ok(forwarded) => forwarded,
+                Err(timed_out) => Err(timed_out.into()),
+            };
+
+            match forwarded {
+                Ok(mut response) => {
+                    if let (Some((client, tap)), StatusCode::SWITCHING_PROTOCOLS) = (upgrade, response.status()) {
+                        let pod = hyper::upgrade::on(&mut response);
+                        tokio::spawn(splice_upgraded(host, client, pod, tap));
+                    }
+                    Ok(response)
+                }
+                Err(err) => Ok(error_response(&forwarder, &host, err).await),
             }
+        };
+        Box::pin(future)
+    }
+}
+
+/// Pods are diagnosed here, once the retries gave up, rather than on every failed attempt.
+async fn error_response(forwarder: &Forwarder, host: &str, err: ForwardError) -> Response<hyper::Body> {
+    let diagnostics = match err.diagnosed_pods() {
+        Some((namespace, pods)) => Some(diagnose(&forwarder.client, &namespace, &pods).await),
+        None => None,
+    };
+    match &diagnostics {
+        Some(diagnostics) => log::error!("[{}] forwarding failed: {} - {}", host, err, diagnostics),
+        None => log::error!("[{}] forwarding failed: {}", host, err),
+    }
+    err.to_response(diagnostics.as_ref())
+}
 
-            while retries < MAX_RETRIES { 
-                retries += 1;
+/// Answers `CONNECT host:port` once the tunnel (to a pod of the service or straight to the host) is open.
+async fn connect(forwarder: Arc<Forwarder>, mut req: Request<hyper::Body>) -> Response<hyper::Body> {
+    let host = req.uri().authority().map(|authority| authority.to_string()).unwrap_or_default();
+    let client = hyper::upgrade::on(&mut req);
+    match open_tunnel(&forwarder, &host).await {
+        Ok((stream, pod)) => {
+            tokio::spawn(splice_tunnel(forwarder, host, client, stream, pod));
+            Response::new(hyper::Body::empty())
+        }
+        Err(err) => error_response(&forwarder, &host, err).await,
+    }
+}
 
-                let client = client.clone();
-                let body = cloned_reply.clone();
-                let upstream_connection = upstream_connection.clone();
+/// Returns the stream of the tunnel together with the pod it goes to (none for hosts outside the cluster).
+async fn open_tunnel(forwarder: &Forwarder, host: &str) -> Result<(Box<dyn PortStream>, Option<String>), ForwardError> {
+    if TargetHost::is_cluster_host(host, &forwarder.config.cluster_domain) {
+        let target = TargetHost::parse(host, &forwarder.config.cluster_domain, &forwarder.config.default_namespace)?;
+        let (stream, pod) = forwarder.open_stream(&target, host).await?;
+        return Ok((stream, Some(pod)));
+    }
 
-                let mut request = Request::builder()
+    match forwarder.config.external_hosts {
+        ExternalHosts::Reject => Err(ForwardError::ExternalHost { host: String::from(host) }),
+        ExternalHosts::PassThrough => {
+            let stream = with_timeout(Phase::Attempt, forwarder.config.timeouts.attempt, TcpStream::connect(host)).await?
+                .map_err(|source| ForwardError::ExternalConnect { host: String::from(host), source })?;
+            Ok((Box::new(stream), None))
+        }
+    }
+}
+
+/// Copies data between the client and the tunnel once our response to CONNECT is sent.
+async fn splice_tunnel(forwarder: Arc<Forwarder>, host: String, client: OnUpgrade, mut stream: Box<dyn PortStream>, pod: Option<String>) {
+    let mut client = match client.await {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("[{}] upgrade failed: {}", host, err);
+            return;
+        }
+    };
+
+    log::info!("[{}] tunnel opened", host);
+    let _in_flight = pod.as_deref().map(|pod| forwarder.outstanding.start(pod));
+    match copy_bidirectional(&mut client, &mut stream).await {
+        Ok((sent, received)) => log::info!("[{}] tunnel closed, {} bytes sent, {} bytes received", host, sent, received),
+        Err(err) => log::info!("[{}] tunnel failed: {}", host, err),
+    }
+}
+
+/// Connects client and pod once both sides of the upgrade (e.g. WebSocket handshake) are done.
+async fn splice_upgraded(host: String, client: OnUpgrade, pod: OnUpgrade, tap: Option<UpgradeTap>) {
+    let (client, pod) = match tokio::try_join!(client, pod) {
+        Ok(upgraded) => upgraded,
+        Err(err) => {
+            log::error!("[{}] upgrade failed: {}", host, err);
+            return;
+        }
+    };
+
+    log::info!("[{}] connection upgraded", host);
+    match splice(client, pod, tap).await {
+        Ok((sent, received)) => log::info!("[{}] upgraded connection closed, {} bytes sent, {} bytes received", host, sent, received),
+        Err(err) => log::info!("[{}] upgraded connection failed: {}", host, err),
+    }
+}
+
+/// Forwards the request, retrying it according to the retry policy. Returns the last
+/// response (when its status was retryable) or the last error when retries are done.
+async fn forward_with_retries(forwarder: &Forwarder, target: &TargetHost, host: &str, method: Method, uri: hyper::Uri, mut headers: HeaderMap, body: hyper::Body)
+                                -> Result<Response<hyper::Body>, ForwardError> {
+
+    // HTTP/2 clients send authority instead, HTTP/1 pods need the header
+    if let (false, Ok(value)) = (headers.contains_key(HOST), HeaderValue::from_str(host)) {
+        headers.insert(HOST, value);
+    }
+
+    // once per request, a request which waited in vain is not retried (nor waits again)
+    if let Some(wait) = forwarder.config.wait_for_ready {
+        wait_for_ready_pod(forwarder, target, host, wait).await?;
+    }
+
+    let policy = &forwarder.config.retry;
+    let timeouts = &forwarder.config.timeouts;
+    let budget = forwarder.budgets.get(target);
+    budget.deposit();
+    let started = Instant::now();
+    let mut retries: usize = 0;
+    let max_replay_body_bytes = forwarder.config.max_replay_body_bytes;
+    let replay_limit = max_replay_body_bytes.saturating_add(forwarder.config.spill.as_ref().map_or(0, |spill| spill.max_disk_bytes));
+    // size hint of the body is gone once `LogService` has wrapped it, so the declared length decides
+    let declared_length = headers.get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    let replay_body = match forwarder.config.spill.clone() {
+        _ if declared_length.is_some_and(|length| length > replay_limit as u64) => Err(body),
+        Some(spill) => ReplayBody::try_new_spilling(body, max_replay_body_bytes, spill),
+        None => ReplayBody::try_new(body, max_replay_body_bytes),
+    };
+    let (initial_body, cloned_reply) = match replay_body {
+        Ok(replay_body) => {
+            let cloned_reply = replay_body.clone();
+            (UpstreamBody::Replayable(Box::new(replay_body)), Some(cloned_reply))
+        }
+        Err(body) => {
+            log::info!("[{}] body of {} {} is too large to be buffered, it won't be retried", host, method, uri);
+            (UpstreamBody::Streaming(body), None)
+        }
+    };
+    //initial request - because of original request body needs to be read (probably?)
+    let mut initial_body = Some(initial_body);
+    // retryable response (or the error) is returned when retrying is not possible anymore
+    let mut last_outcome;
+
+    loop {
+        let body = match (initial_body.take(), &cloned_reply) {
+            (Some(initial_body), _) => initial_body,
+            (None, Some(cloned_reply)) => UpstreamBody::Replayable(Box::new(cloned_reply.clone())),
+            (None, None) => unreachable!("streamed bodies are never retried"),
+        };
+
+        let mut request = Request::builder()
+            .uri(uri.clone())
+            .method(method.to_string().as_str())
+            .body(body)
+            .unwrap();
+        
+        request.headers_mut().extend(headers.clone());
+
+        // clones of the replayable body can't be sent at the same time, so only GETs without body are hedged
+        let bodyless = cloned_reply.as_ref().map(|cloned_reply| cloned_reply.is_end_stream()).unwrap_or(false);
+        let hedge_delay = match &forwarder.config.hedge {
+            // upgraded connection can't be raced
+            Some(hedge) if method == Method::GET && bodyless && !is_upgrade(&headers) => forwarder.latencies.hedge_delay(target, hedge),
+            _ => None,
+        };
+
+        let attempt_started = Instant::now();
+        let attempt = match hedge_delay {
+            Some(hedge_delay) => {
+                let mut hedge = Request::builder()
                     .uri(uri.clone())
-                    .method(method.to_string().as_str())
-                    .body(body)
+                    .method(method.clone())
+                    .body(UpstreamBody::Streaming(hyper::Body::empty()))
                     .unwrap();
-                
-                request.headers_mut().extend(headers.clone());
-
-                match perform_forward(client, request, upstream_connection).await {
-                    Ok(response) => {
-                        return Ok(response)
-                    }, 
-                    Err(err) => {
-                        log::error!("unable to port-forward to {}: {}",host, err);
-                    }
-                }
+                hedge.headers_mut().extend(headers.clone());
+                with_timeout(Phase::Attempt, timeouts.attempt, perform_hedged(forwarder, target, host, request, hedge, hedge_delay, &budget)).await
+            }
+            None => with_timeout(Phase::Attempt, timeouts.attempt, perform_forward(forwarder, target, request, None, None)).await,
+        };
+        let attempt = match attempt {
+            Ok(attempt) => attempt,
+            Err(timed_out) => Err(timed_out.into()),
+        };
+        if attempt.is_ok() {
+            forwarder.latencies.record(target, attempt_started.elapsed());
+        }
 
-                //after connection refused or orhter issue with port-forwarding, lets sleep with backoff
-                let sleep_time_ms = 100 * retries as u64;
-                log::info!("waiting {}ms before retrying {} {}", sleep_time_ms, method, uri);
-                sleep(Duration::from_millis(sleep_time_ms)).await;
+        let retryable = match attempt {
+            Ok(response) if retries < policy.max_retries
+                && policy.is_retryable_status(response.status())
+                && policy.is_retryable_method(&method) => {
+                log::error!("[{}] received {}, retry will be performed", host, response.status());
+                last_outcome = Ok(response);
+                true
+            }
+            Ok(response) => {
+                return Ok(response)
+            }, 
+            Err(err) => {
+                log::error!("unable to port-forward to {}: {}",host, err);
+                // nothing reached the pod, so even POST can be sent again
+                let retryable = err.is_not_sent() || policy.is_retryable_method(&method);
+                last_outcome = Err(err);
+                retryable
             }
-            
-            Ok(Response::builder().status(500).body("Unable to port-forward\n".into()).unwrap())
         };
-        Box::pin(future)
+
+        if !retryable || retries >= policy.max_retries {
+            break;
+        }
+        // streamed body is gone, buffered one may have exceeded the limit while it was sent, or still
+        // be sent by the connection of an attempt which timed out (or was answered before reading it)
+        let replayable = cloned_reply.as_ref().map(|cloned_reply| cloned_reply.is_replayable()).unwrap_or(false);
+        if !replayable {
+            log::error!("[{}] body of {} {} can't be replayed, not retrying", host, method, uri);
+            break;
+        }
+        retries += 1;
+
+        //after connection refused or orhter issue with port-forwarding, lets sleep with backoff
+        let backoff = policy.backoff(retries);
+        if started.elapsed() + backoff > policy.deadline {
+            log::error!("[{}] retry deadline of {:?} exceeded for {} {}", host, policy.deadline, method, uri);
+            break;
+        }
+        if budget.withdraw().is_err() {
+            log::error!("[{}] retry budget exhausted, not retrying {} {}", host, method, uri);
+            break;
+        }
+        log::info!("waiting {}ms before retrying {} {}", backoff.as_millis(), method, uri);
+        sleep(backoff).await;
     }
-}
 
-fn take(upstream_connection: Arc<Mutex<Option<SendRequest<ReplayBody<hyper::Body>>>>>) -> Option<SendRequest<ReplayBody<hyper::Body>>> {
-    upstream_connection.lock().unwrap().take()
+    last_outcome
 }
 
-fn give_it_back(sender: SendRequest<ReplayBody<hyper::Body>>, upstream_connection: Arc<Mutex<Option<SendRequest<ReplayBody<hyper::Body>>>>>) {
-    upstream_connection.lock().unwrap().replace(sender);
+/// Sends the request and, when it isn't answered within `delay`, the same request to another pod
+/// (if the retry budget allows it). Whichever response comes first is returned, the other request
+/// is cancelled by dropping it.
+async fn perform_hedged(forwarder: &Forwarder, target: &TargetHost, host: &str, primary: Request<UpstreamBody>, hedge: Request<UpstreamBody>,
+                        delay: Duration, budget: &Budget) -> Result<Response<hyper::Body>, ForwardError> {
+
+    let primary_pod = Mutex::new(None);
+    let primary = perform_forward(forwarder, target, primary, None, Some(&primary_pod));
+    tokio::pin!(primary);
+    let delay = sleep(delay);
+    tokio::pin!(delay);
+
+    let primary = match select(primary, delay).await {
+        Either::Left((outcome, _)) => return outcome,
+        Either::Right((_, primary)) => primary,
+    };
+    if budget.withdraw().is_err() {
+        log::info!("[{}] retry budget exhausted, not hedging", host);
+        return primary.await;
+    }
+
+    let avoid = primary_pod.lock().unwrap().clone();
+    log::info!("[{}] no response from pod {} yet, sending hedged request", host, avoid.as_deref().unwrap_or("(not connected)"));
+    let hedge = perform_forward(forwarder, target, hedge, avoid.as_deref(), None);
+    tokio::pin!(hedge);
+
+    match select(primary, hedge).await {
+        Either::Left((Ok(response), _)) | Either::Right((Ok(response), _)) => Ok(response),
+        Either::Left((Err(err), hedge)) => {
+            log::info!("[{}] request failed, waiting for the hedged one: {}", host, err);
+            hedge.await
+        }
+        Either::Right((Err(err), primary)) => {
+            log::info!("[{}] hedged request failed, waiting for the original one: {}", host, err);
+            primary.await
+        }
+    }
 }
 
-async fn perform_forward(client: Client, req: Request<ReplayBody<hyper::Body>>, upstream_connection: Arc<Mutex<Option<SendRequest<ReplayBody<hyper::Body>>>>>) -> Result<Response<hyper::Body>, Box<dyn Error + Send + Sync>> {
+/// Sends request over a pooled (or new) connection. New connections avoid pod `avoid` when there
+/// is another ready one, pod the request is sent to is stored in `picked`.
+async fn perform_forward(forwarder: &Forwarder, target: &TargetHost, req: Request<UpstreamBody>, avoid: Option<&str>, picked: Option<&Mutex<Option<String>>>)
+                                -> Result<Response<hyper::Body>, ForwardError> {
 
     let headers = req.headers().clone();
     let host = String::from(headers.get("host").unwrap().to_str().unwrap());
 
-    let host_and_namespace: Vec<&str> = host.split('.').collect();
+    let pool_key = PoolKey::new(&forwarder.config.cluster, target);
+    let usable = |pod: &str| Some(pod) != avoid && !forwarder.outliers.is_ejected(pod) && !forwarder.health.is_unhealthy(target, pod);
+    if let Some(shared) = forwarder.pool.multiplexed(&pool_key, usable) {
+        log::info!("[{}] using shared http2 connection to pod {}", host, shared.pod);
+        return send_multiplexed(forwarder, shared, req, &host, picked).await;
+    }
+
+    let permit = forwarder.pool.acquire(&pool_key).await;
+    let mut connection = match forwarder.pool.checkout(&pool_key, usable) {
+        Some(already_opened) => {
+            log::info!("[{}] using already opened conenction to pod {}", host, already_opened.pod);
+            already_opened
+        }
+        None => {
+            let opening = forwarder.pool.opening(&pool_key).await;
+            // request which held the lock might have opened HTTP/2 connection meanwhile
+            if let Some(shared) = forwarder.pool.multiplexed(&pool_key, usable) {
+                drop((opening, permit));
+                log::info!("[{}] using shared http2 connection to pod {}", host, shared.pod);
+                return send_multiplexed(forwarder, shared, req, &host, picked).await;
+            }
 
-    let maybe_already_opened = take(upstream_connection.clone());
-    if let Some(mut already_opened) = maybe_already_opened {
-        log::info!("[{}] using already opened conenction for {}", host, host);
-        let rsp = Ok(already_opened.send_request(req).await?);   
-        give_it_back(already_opened, upstream_connection);
-        return rsp;
+            log::info!("[{}] no opened connection for {}", host, host);
+            match open_connection(forwarder, target, &host, avoid).await? {
+                UpstreamConnection::Http1(connection) => {
+                    forwarder.pool.mark_http1(&pool_key);
+                    connection
+                }
+                UpstreamConnection::Http2(shared) => {
+                    // HTTP/2 connections are not limited by the permit, they are shared instead
+                    forwarder.pool.share(pool_key, shared.clone());
+                    drop((opening, permit));
+                    return send_multiplexed(forwarder, shared, req, &host, picked).await;
+                }
+            }
+        }
+    };
+    if let Some(picked) = picked {
+        *picked.lock().unwrap() = Some(connection.pod.clone());
     }
-    
-    log::info!("[{}] no opened connection for {}", host, host);
 
-    if host_and_namespace.len() != 2 {
-        log::error!("[{}] received host has uprasable format: {}", host, host);
-        return Ok(Response::builder().status(500).body("Incorrect format of the received host\n".into()).unwrap());
+    let req = for_upstream(req, &host, false);
+    let in_flight = forwarder.outstanding.start(&connection.pod);
+    let resp = connection.sender.send_request(req).await;
+    drop(in_flight);
+    record_outcome(forwarder, &connection.pod, &resp);
+
+    // connection goes back to the pool once it is ready again, or is dropped if it died
+    forwarder.pool.checkin(pool_key, connection, permit);
+
+    resp.map_err(ForwardError::from_upstream)
+}
+
+async fn send_multiplexed(forwarder: &Forwarder, connection: MultiplexedConnection, req: Request<UpstreamBody>, host: &str, picked: Option<&Mutex<Option<String>>>)
+                                -> Result<Response<hyper::Body>, ForwardError> {
+    if let Some(picked) = picked {
+        *picked.lock().unwrap() = Some(connection.pod.clone());
     }
 
-    let application_name = host_and_namespace[0];
-    let namespace = host_and_namespace[1];
-    log::info!("[{}] application_name {} namespace {}", host, application_name, namespace);
+    let req = for_upstream(req, host, true);
+    let in_flight = forwarder.outstanding.start(&connection.pod);
+    let resp = connection.send_request(req).await;
+    drop(in_flight);
+    record_outcome(forwarder, &connection.pod, &resp);
 
-    let port = get_stream(client.clone(), application_name, &host, namespace).await?;    
-    let (mut sender, connection) =  Builder::new().handshake(port).await?;
+    resp.map_err(ForwardError::from_upstream)
+}
 
-    let moved_host = host.clone();
+fn record_outcome(forwarder: &Forwarder, pod: &str, resp: &Result<Response<hyper::Body>, hyper::Error>) {
+    match resp {
+        Ok(resp) if resp.status().is_server_error() => forwarder.outliers.record_failure(pod),
+        Ok(_) => forwarder.outliers.record_success(pod),
+        // connection closed by us, pod is not to blame
+        Err(err) if err.is_canceled() => {}
+        Err(_) => forwarder.outliers.record_failure(pod),
+    }
+}
+
+/// Freshly opened upstream connection, HTTP/2 ones are shared by concurrent requests.
+enum UpstreamConnection {
+    Http1(PooledConnection),
+    Http2(MultiplexedConnection),
+}
+
+/// HTTP/2 requests carry scheme and authority in the uri, HTTP/1 ones only path (authority is in `Host`).
+fn for_upstream(mut req: Request<UpstreamBody>, host: &str, http2: bool) -> Request<UpstreamBody> {
+    let path_and_query = req.uri().path_and_query().map(|path_and_query| path_and_query.as_str()).unwrap_or("/");
+    let uri = match http2 {
+        true => format!("http://{}{}", host, path_and_query).parse(),
+        false => path_and_query.parse(),
+    };
+
+    // CONNECT and similar have no path, they are sent as they came
+    if let Ok(uri) = uri {
+        if req.uri().path_and_query().is_some() {
+            *req.uri_mut() = uri;
+        }
+    }
+    *req.version_mut() = if http2 { Version::HTTP_2 } else { Version::HTTP_11 };
+    req
+}
+
+async fn open_connection(forwarder: &Forwarder, target: &TargetHost, host: &str, avoid: Option<&str>) -> Result<UpstreamConnection, ForwardError> {
+    log::info!("[{}] application_name {} namespace {}", host, target.application_name, target.namespace);
+
+    let (port, pod, protocol) = get_stream(forwarder, target, host, avoid).await?;
+    let http2 = protocol == UpstreamProtocol::Http2;
+    let (sender, connection) = match Builder::new().http2_only(http2).handshake(port).await {
+        Ok(handshake) => handshake,
+        Err(err) => {
+            forwarder.outliers.record_failure(&pod);
+            return Err(ForwardError::Handshake(err));
+        }
+    };
+
+    let moved_host = String::from(host);
     tokio::spawn(async move {
         if let Err(e) = connection.await {
             log::error!("[{}] Error in connection: {}", moved_host,  e);
@@ -265,46 +894,247 @@ async fn perform_forward(client: Client, req: Request<ReplayBody<hyper::Body>>,
         log::info!("[{}] connection will be closed.", moved_host)
     });
 
-    let resp = Ok(sender.send_request(req).await?);
+    match http2 {
+        true => Ok(UpstreamConnection::Http2(MultiplexedConnection::new(sender, pod))),
+        false => Ok(UpstreamConnection::Http1(PooledConnection { sender, pod })),
+    }
+}
 
-    {
-        // here I guess we succedded, so, sender is valid
-        let mut connection_state = upstream_connection.lock().unwrap();
-        connection_state.replace(sender);
+async fn get_stream(forwarder: &Forwarder, target: &TargetHost, host: &str, avoid: Option<&str>) 
+                                -> Result<(Box<dyn PortStream>, String, UpstreamProtocol), ForwardError> {
+                                     
+    let application_name = target.application_name.as_str();
+    let namespace = target.namespace.as_str();
+    let timeouts = &forwarder.config.timeouts;
+    let discovered = with_timeout(Phase::Discovery, timeouts.discovery, async {
+        let backend = get_backend(forwarder, target, host).await?;
+        log::info!("[{}] selector= {:?}", host, backend.selector);
+        let found_pods = forwarder.discovery.find_pods(namespace, &backend.selector).await?;
+        Ok::<_, ForwardError>((backend, found_pods))
+    });
+    let (backend, found_pods) = discovered.await??;
+
+    // service scaled to zero (or in the middle of rollout) is there, it just has no pods to forward to
+    if found_pods.is_empty() && !backend.has_service {
+        return Err(ForwardError::NoSuchService {
+            application_name: String::from(application_name),
+            namespace: String::from(namespace),
+        })
+    }
+                                    
+    let mut ready_pods: Vec<&Pod> = found_pods.iter().map(Arc::as_ref).filter(|pod| is_pod_ready(pod)).collect();
+    // cache has no stable order, round-robin needs one
+    ready_pods.sort_by_key(|pod| pod.name_any());
+    if ready_pods.is_empty() {
+        return Err(ForwardError::NoReadyPods {
+            application_name: String::from(application_name),
+            namespace: String::from(namespace),
+            pods: found_pods,
+        })
     }
 
-    return resp;
+    // probes may be stricter than the readiness probe of the pod
+    let ready = ready_pods.len();
+    ready_pods.retain(|pod| !forwarder.health.is_unhealthy(target, &pod_key(pod)));
+    if ready_pods.is_empty() {
+        return Err(ForwardError::NoHealthyPods {
+            application_name: String::from(application_name),
+            namespace: String::from(namespace),
+            ready,
+        })
+    }
 
+    // hedged request goes to a different pod, unless there is no other
+    if ready_pods.iter().any(|pod| Some(pod_key(pod).as_str()) != avoid) {
+        ready_pods.retain(|pod| Some(pod_key(pod).as_str()) != avoid);
+    }
+    // pods which keep failing are skipped, unless all of them do
+    if ready_pods.iter().any(|pod| !forwarder.outliers.is_ejected(&pod_key(pod))) {
+        ready_pods.retain(|pod| !forwarder.outliers.is_ejected(&pod_key(pod)));
+    }
+
+    let target_pod = ready_pods[forwarder.balancer.pick(target, &ready_pods)];
+    let container_port = get_container_port(target_pod, &backend.target_port)?;
+    log::info!("[{}] forwarding to pod {:?} port {}", host, &target_pod.name_any(), container_port);
+    
+    let pods: Api<Pod> = Api::namespaced(forwarder.client.clone(), namespace);
+    let stream = match with_timeout(Phase::PortForward, timeouts.port_forward, forwarder.sessions.stream(&pods, target_pod, container_port)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(source)) => {
+            forwarder.outliers.record_failure(&pod_key(target_pod));
+            return Err(ForwardError::PortForward {
+                pod: Arc::new(target_pod.clone()),
+                source: Box::new(source),
+            })
+        }
+        Err(timed_out) => {
+            forwarder.outliers.record_failure(&pod_key(target_pod));
+            return Err(timed_out.into())
+        }
+    };
+    Ok((stream, pod_key(target_pod), backend.protocol))
 }
 
-async fn get_stream(client: Client, application_name: &str, host: &str, namespace: &str) 
-                                -> Result<impl AsyncRead + AsyncWrite + Unpin, Box<dyn Error + Send + Sync>> {
-                                     
-    let pods: Api<Pod> = Api::namespaced(client, namespace);
-    let selector = format!("app={}", application_name);
-    log::info!("[{}] selector= {:?}", host, selector);
-    let lp = ListParams::default().labels(&selector); 
-    let found_pods = match pods.list(&lp).await {
-        Ok(found_pods) => found_pods,
-        Err(_) => return Err(Box::new(RuntimeError::from("Unable to list pods"))),
+/// During rollouts pods are starting (or not even created yet), so the request waits for one,
+/// unless nothing at all points to the target - then it was probably mistyped.
+async fn wait_for_ready_pod(forwarder: &Forwarder, target: &TargetHost, host: &str, wait: Duration) -> Result<(), ForwardError> {
+    let namespace = target.namespace.as_str();
+    let discovered = with_timeout(Phase::Discovery, forwarder.config.timeouts.discovery, async {
+        let backend = get_backend(forwarder, target, host).await?;
+        let found_pods = forwarder.discovery.find_pods(namespace, &backend.selector).await?;
+        Ok::<_, ForwardError>((backend, found_pods))
+    });
+    let (backend, found_pods) = discovered.await??;
+    if found_pods.iter().any(|pod| is_pod_ready(pod)) || (!backend.has_service && found_pods.is_empty()) {
+        return Ok(());
+    }
+
+    log::info!("[{}] no ready pods, waiting up to {}ms for one", host, wait.as_millis());
+    if let Ok(ready) = timeout(wait, forwarder.discovery.wait_for_pods(namespace, &backend.selector, is_pod_ready)).await {
+        return ready.map(|_| ()).map_err(ForwardError::from);
+    }
+
+    let found_pods = forwarder.discovery.find_pods(namespace, &backend.selector).await?;
+    if found_pods.is_empty() && !backend.has_service {
+        return Err(ForwardError::NoSuchService {
+            application_name: target.application_name.clone(),
+            namespace: String::from(namespace),
+        });
+    }
+    Err(ForwardError::NoReadyPods {
+        application_name: target.application_name.clone(),
+        namespace: String::from(namespace),
+        pods: found_pods,
+    })
+}
+
+/// Pods backing an application, and the port (as seen by the pod) traffic should go to.
+struct Backend {
+    selector: BTreeMap<String, String>,
+    target_port: IntOrString,
+    /// `false` when the `app` label fallback is used
+    has_service: bool,
+    protocol: UpstreamProtocol,
+}
+
+/// HTTP version spoken to the pods, HTTP/2 is used without TLS (h2c with prior knowledge).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpstreamProtocol {
+    Http1,
+    Http2,
+}
+
+/// `appProtocol` values of Service ports speaking HTTP/2 without TLS.
+const HTTP2_APP_PROTOCOLS: [&str; 4] = ["kubernetes.io/h2c", "h2c", "http2", "grpc"];
+
+/// Targets listed in `ForwardingConfig::http2_targets`, those without port match all ports.
+fn is_http2_target(forwarder: &Forwarder, target: &TargetHost) -> bool {
+    let any_port = TargetHost { port: None, ..target.clone() };
+    forwarder.config.http2_targets.contains(target) || forwarder.config.http2_targets.contains(&any_port)
+}
+
+/// Resolves pods and target port for given application. `Service` called `application_name`
+/// is the source of truth (its `spec.selector` and `targetPort` are used), when there is
+/// no such `Service` we fall back to the `app=<application_name>` label and requested port
+/// (or 8080 when there is no port in the host).
+async fn get_backend(forwarder: &Forwarder, target: &TargetHost, host: &str)
+                                -> Result<Backend, ForwardError> {
+
+    let application_name = target.application_name.as_str();
+    let namespace = target.namespace.as_str();
+    let requested_port = target.port;
+    let service = match forwarder.discovery.get_service(namespace, application_name).await? {
+        Some(service) => service,
+        None => {
+            log::info!("[{}] no service {} in namespace {}, using app label", host, application_name, namespace);
+            let port = requested_port.unwrap_or(DEFAULT_PORT);
+            let selector = BTreeMap::from([(String::from("app"), String::from(application_name))]);
+            let protocol = if is_http2_target(forwarder, target) { UpstreamProtocol::Http2 } else { UpstreamProtocol::Http1 };
+            return Ok(Backend { selector, target_port: IntOrString::Int(port.into()), has_service: false, protocol });
+        }
     };
 
-    if found_pods.items.len() == 0 {
-        let err_msg = format!("No pods found for host {host} - extract: application_name: {application_name} and namespace {namespace}");
-        log::error!("[{}] {}", host, err_msg);
-        return Err(Box::new(RuntimeError::from(&err_msg)))
+    let spec = service.spec.clone().unwrap_or_default();
+    let selector = spec.selector.unwrap_or_default();
+
+    if selector.is_empty() {
+        return Err(ForwardError::NoSelector {
+            application_name: String::from(application_name),
+            namespace: String::from(namespace),
+        })
     }
-                                    
-    let target_pod = &found_pods.items[0];
-    log::info!("[{}] forwarding to pod {:?}", host, &target_pod.name());
-    
-    let mut pf = match pods.portforward(&target_pod.name(), &[8080]).await {
-        Ok(pf) => pf,
-        Err(_) => return Err(Box::new(RuntimeError::from("Unable to obtain port-forwarder"))),
+
+    let service_ports = spec.ports.unwrap_or_default();
+    let service_port = match requested_port {
+        Some(requested_port) => service_ports.iter().find(|port| port.port == i32::from(requested_port)),
+        None => service_ports.first(),
     };
 
-    match pf.take_stream(8080) {
-        Some(stream) => Ok(stream),
-        None => Err(Box::new(RuntimeError::from("Unable to obtain stream")))
+    let service_port = match service_port {
+        Some(service_port) => service_port,
+        None => {
+            return Err(ForwardError::NoServicePort {
+                application_name: String::from(application_name),
+                namespace: String::from(namespace),
+                port: requested_port,
+            })
+        }
+    };
+
+    // when targetPort is not set, k8s uses the same value as port
+    let target_port = service_port.target_port.clone()
+        .unwrap_or(IntOrString::Int(service_port.port));
+
+    let app_protocol = service_port.app_protocol.as_deref().unwrap_or_default();
+    let protocol = if is_http2_target(forwarder, target) || HTTP2_APP_PROTOCOLS.contains(&app_protocol) {
+        UpstreamProtocol::Http2
+    } else {
+        UpstreamProtocol::Http1
+    };
+
+    Ok(Backend { selector, target_port, has_service: true, protocol })
+}
+
+/// Translates Service's `targetPort` into the container port of the given pod.
+/// Named ports are looked up in the pod's containers.
+fn get_container_port(pod: &Pod, target_port: &IntOrString) -> Result<u16, ForwardError> {
+    let port = match target_port {
+        IntOrString::Int(port) => Some(*port),
+        IntOrString::String(name) => pod.spec.iter()
+            .flat_map(|spec| spec.containers.iter())
+            .flat_map(|container| container.ports.iter().flatten())
+            .find(|port| port.name.as_deref() == Some(name.as_str()))
+            .map(|port| port.container_port),
+    };
+
+    match port.and_then(|port| u16::try_from(port).ok()) {
+        Some(port) => Ok(port),
+        None => {
+            let port = match target_port {
+                IntOrString::Int(port) => port.to_string(),
+                IntOrString::String(name) => name.clone(),
+            };
+            Err(ForwardError::NoContainerPort { pod: pod.name_any(), port })
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Pod can receive traffic when it is running, is not being deleted and reports `Ready` condition.
+fn is_pod_ready(pod: &Pod) -> bool {
+    if pod.metadata.deletion_timestamp.is_some() {
+        return false;
+    }
+
+    let status = match pod.status.as_ref() {
+        Some(status) => status,
+        None => return false,
+    };
+
+    if status.phase.as_deref() != Some("Running") {
+        return false;
+    }
+
+    status.conditions.iter()
+        .flatten()
+        .any(|condition| condition.type_ == "Ready" && condition.status == "True")
+}
@@ -0,0 +1,495 @@
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::{ready, BoxFuture};
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, HOST, TRANSFER_ENCODING, UPGRADE};
+use hyper::server::conn::Http;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Request, Response, StatusCode, Version};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tower::{Layer, Service};
+
+use crate::upgrade::is_upgrade;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+/// default `SETTINGS_MAX_FRAME_SIZE`, the server can't have announced anything else yet
+const MAX_FRAME_SIZE: usize = 16384;
+
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_CONTINUATION: u8 = 0x9;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+/// Upgrades HTTP/1.1 connections to h2c (RFC 7540, section 3.2), for clients which don't speak
+/// HTTP/2 with prior knowledge (e.g. `curl --http2`).
+#[derive(Debug, Clone)]
+pub struct H2cUpgradeLayer;
+
+impl<S> Layer<S> for H2cUpgradeLayer {
+    type Service = H2cUpgrade<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Self::Service { inner }
+    }
+}
+
+/// Answers `Upgrade: h2c` with 101 and serves the connection over HTTP/2 with the inner service
+/// afterwards. hyper can't take the upgrade request over as stream 1, so the request is replayed to
+/// its HTTP/2 server as if the client sent it as the first stream (HEADERS frame right after
+/// the client's SETTINGS, which get the values of `HTTP2-Settings` header merged in).
+#[derive(Debug, Clone)]
+pub struct H2cUpgrade<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for H2cUpgrade<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response<Body>, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if !is_h2c_upgrade(&req) {
+            return Box::pin(self.inner.call(req));
+        }
+        let settings = match h2c_settings(&req) {
+            Some(settings) => settings,
+            None => {
+                // declined, so the request is answered over HTTP/1.1 instead of being passed to the pod as an upgrade
+                for name in [UPGRADE, CONNECTION, HeaderName::from_static("http2-settings")] {
+                    req.headers_mut().remove(name);
+                }
+                return Box::pin(self.inner.call(req));
+            }
+        };
+
+        let first_stream = header_block(&req);
+        let client = hyper::upgrade::on(&mut req);
+        tokio::spawn(serve_upgraded(client, settings, first_stream, self.inner.clone()));
+
+        let switching = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "h2c")
+            .body(Body::empty())
+            .unwrap();
+        Box::pin(ready(Ok(switching)))
+    }
+}
+
+fn is_h2c_upgrade(req: &Request<Body>) -> bool {
+    let upgrade_h2c = req.headers().get_all(UPGRADE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"));
+    req.version() == Version::HTTP_11 && is_upgrade(req.headers()) && upgrade_h2c
+}
+
+/// Decoded `HTTP2-Settings` of an h2c upgrade request, none when the upgrade is declined. Requests with a body
+/// are answered over HTTP/1.1 (the server may ignore the upgrade), so the body never has to be carried over to the first stream.
+fn h2c_settings(req: &Request<Body>) -> Option<Bytes> {
+    if !http_body::Body::is_end_stream(req.body()) || req.headers().contains_key(TRANSFER_ENCODING) {
+        return None;
+    }
+
+    let mut settings = req.headers().get_all("http2-settings").iter();
+    match (settings.next(), settings.next()) {
+        (Some(encoded), None) => decode_base64url(encoded.to_str().ok()?)
+            .filter(|settings| settings.len() % 6 == 0)
+            .map(Bytes::from),
+        _ => None,
+    }
+}
+
+async fn serve_upgraded<S>(client: OnUpgrade, settings: Bytes, first_stream: Bytes, service: S)
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+    S::Future: Send + 'static,
+{
+    let mut client = match client.await {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("h2c upgrade failed: {}", err);
+            return;
+        }
+    };
+
+    let start = match connection_start(&mut client, &settings, &first_stream).await {
+        Ok(start) => start,
+        Err(err) => {
+            log::error!("h2c upgraded connection failed: {}", err);
+            return;
+        }
+    };
+
+    log::info!("connection upgraded to h2c");
+    let io = Rewind { prefix: start, inner: client };
+    if let Err(err) = Http::new().http2_only(true).serve_connection(io, service).await {
+        log::info!("h2c connection closed: {}", err);
+    }
+}
+
+/// Reads client's preface and SETTINGS frame, and returns what the HTTP/2 server should read
+/// in their place - the preface, SETTINGS merged with those of the upgrade request
+/// (later ones win) and HEADERS of the upgrade request as stream 1.
+async fn connection_start<T: AsyncRead + Unpin>(client: &mut T, settings: &[u8], first_stream: &[u8]) -> io::Result<Bytes> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let mut preface = [0; PREFACE.len()];
+    client.read_exact(&mut preface).await?;
+    if preface != PREFACE {
+        return Err(invalid("client did not send HTTP/2 preface"));
+    }
+
+    let mut header = [0; FRAME_HEADER_LEN];
+    client.read_exact(&mut header).await?;
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if header[3] != FRAME_SETTINGS || header[4] & FLAG_ACK != 0 || length > MAX_FRAME_SIZE {
+        return Err(invalid("client did not start with SETTINGS frame"));
+    }
+    let mut client_settings = vec![0; length];
+    client.read_exact(&mut client_settings).await?;
+
+    let mut start = BytesMut::new();
+    start.put_slice(PREFACE);
+    put_frame(&mut start, FRAME_SETTINGS, 0, 0, &[settings, &client_settings].concat());
+    put_headers(&mut start, 1, first_stream);
+    Ok(start.freeze())
+}
+
+/// HPACK block of the upgrade request, every field is a literal without indexing,
+/// so the client's and server's dynamic tables are left as they are.
+fn header_block(req: &Request<Body>) -> Bytes {
+    let authority = req.uri().authority()
+        .map(|authority| authority.as_str().as_bytes())
+        .or_else(|| req.headers().get(HOST).map(HeaderValue::as_bytes));
+    let path = req.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+
+    let mut block = BytesMut::new();
+    put_literal(&mut block, b":method", req.method().as_str().as_bytes());
+    put_literal(&mut block, b":scheme", b"http");
+    if let Some(authority) = authority {
+        put_literal(&mut block, b":authority", authority);
+    }
+    put_literal(&mut block, b":path", path.as_bytes());
+    for (name, value) in req.headers() {
+        if !is_connection_specific(name, value) {
+            put_literal(&mut block, name.as_str().as_bytes(), value.as_bytes());
+        }
+    }
+    block.freeze()
+}
+
+/// Fields HTTP/2 doesn't allow (RFC 7540, section 8.1.2.2), Host is sent as `:authority`.
+fn is_connection_specific(name: &HeaderName, value: &HeaderValue) -> bool {
+    match name.as_str() {
+        "connection" | "upgrade" | "http2-settings" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "host" => true,
+        "te" => value != "trailers",
+        _ => false,
+    }
+}
+
+fn put_literal(block: &mut BytesMut, name: &[u8], value: &[u8]) {
+    block.put_u8(0x00);
+    put_string(block, name);
+    put_string(block, value);
+}
+
+/// String literal without Huffman coding, its length is an integer with 7 bit prefix (RFC 7541, section 5.1).
+fn put_string(block: &mut BytesMut, string: &[u8]) {
+    let mut length = string.len();
+    if length < 0x7f {
+        block.put_u8(length as u8);
+    } else {
+        block.put_u8(0x7f);
+        length -= 0x7f;
+        while length >= 0x80 {
+            block.put_u8((length % 0x80) as u8 | 0x80);
+            length /= 0x80;
+        }
+        block.put_u8(length as u8);
+    }
+    block.put_slice(string);
+}
+
+fn put_frame(buf: &mut BytesMut, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    buf.put_uint(payload.len() as u64, 3);
+    buf.put_u8(kind);
+    buf.put_u8(flags);
+    buf.put_u32(stream);
+    buf.put_slice(payload);
+}
+
+/// HEADERS frame (ending the stream, the request has no body), followed by CONTINUATION frames
+/// when the block doesn't fit.
+fn put_headers(buf: &mut BytesMut, stream: u32, block: &[u8]) {
+    let mut chunks = block.chunks(MAX_FRAME_SIZE).peekable();
+    let (mut kind, mut flags) = (FRAME_HEADERS, FLAG_END_STREAM);
+    while let Some(chunk) = chunks.next() {
+        let end_headers = if chunks.peek().is_none() { FLAG_END_HEADERS } else { 0 };
+        put_frame(buf, kind, flags | end_headers, stream, chunk);
+        (kind, flags) = (FRAME_CONTINUATION, 0);
+    }
+}
+
+/// `HTTP2-Settings` is base64url without padding (RFC 4648, section 5).
+fn decode_base64url(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut bits, mut count) = (0u32, 0);
+    for byte in encoded.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Upgraded connection whose first bytes are replaced by the prepared `prefix`.
+struct Rewind<T> {
+    prefix: Bytes,
+    inner: T,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.prefix.has_remaining() {
+            let read = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..read]);
+            self.prefix.advance(read);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use hyper::service::service_fn;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::oneshot;
+    use super::*;
+
+    /// `SETTINGS_MAX_CONCURRENT_STREAMS` = 100, `SETTINGS_INITIAL_WINDOW_SIZE` = 10485760, `SETTINGS_ENABLE_PUSH` = 0
+    const CURL_SETTINGS: &str = "AAMAAABkAAQAoAAAAAIAAAAA";
+
+    /// `(kind, flags, stream, payload)` of every frame in the buffer.
+    fn frames(mut buf: &[u8]) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while buf.has_remaining() {
+            let length = buf.get_uint(3) as usize;
+            let (kind, flags, stream) = (buf.get_u8(), buf.get_u8(), buf.get_u32());
+            frames.push((kind, flags, stream, buf[..length].to_vec()));
+            buf.advance(length);
+        }
+        frames
+    }
+
+    fn upgrade_request(headers: &[(&str, String)]) -> Request<Body> {
+        let mut req = Request::get("/path?query=1")
+            .header(HOST, "app.default")
+            .header(CONNECTION, "Upgrade, HTTP2-Settings")
+            .header(UPGRADE, "h2c")
+            .header("http2-settings", CURL_SETTINGS);
+        for (name, value) in headers {
+            req = req.header(*name, value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn string_lengths_use_7_bit_prefix() {
+        let encoded = |length: usize| {
+            let mut block = BytesMut::new();
+            put_string(&mut block, &vec![b'a'; length]);
+            block[..block.len() - length].to_vec()
+        };
+        assert_eq!(encoded(10), [10]);
+        assert_eq!(encoded(126), [0x7e]);
+        assert_eq!(encoded(127), [0x7f, 0x00]);
+        assert_eq!(encoded(254), [0x7f, 0x7f]);
+        assert_eq!(encoded(255), [0x7f, 0x80, 0x01]);
+        assert_eq!(encoded(300), [0x7f, 0xad, 0x01]);
+        assert_eq!(encoded(20000), [0x7f, 0xa1, 0x9b, 0x01]);
+    }
+
+    #[test]
+    fn literal_is_not_indexed() {
+        let mut block = BytesMut::new();
+        put_literal(&mut block, b"x-name", b"value");
+        assert_eq!(&block[..], b"\x00\x06x-name\x05value");
+    }
+
+    #[test]
+    fn short_header_block_fits_one_frame() {
+        let mut buf = BytesMut::new();
+        put_headers(&mut buf, 1, b"block");
+        assert_eq!(frames(&buf), vec![(FRAME_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS, 1, b"block".to_vec())]);
+    }
+
+    #[test]
+    fn long_header_block_is_continued() {
+        let block: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let mut buf = BytesMut::new();
+        put_headers(&mut buf, 1, &block);
+
+        let frames = frames(&buf);
+        let kinds: Vec<(u8, u8, u32, usize)> = frames.iter().map(|(kind, flags, stream, payload)| (*kind, *flags, *stream, payload.len())).collect();
+        assert_eq!(kinds, vec![
+            (FRAME_HEADERS, FLAG_END_STREAM, 1, MAX_FRAME_SIZE),
+            (FRAME_CONTINUATION, 0, 1, MAX_FRAME_SIZE),
+            (FRAME_CONTINUATION, FLAG_END_HEADERS, 1, 40000 - 2 * MAX_FRAME_SIZE),
+        ]);
+        assert_eq!(frames.into_iter().flat_map(|(_, _, _, payload)| payload).collect::<Vec<u8>>(), block);
+    }
+
+    #[test]
+    fn decodes_base64url() {
+        assert_eq!(decode_base64url("YQ").unwrap(), b"a");
+        assert_eq!(decode_base64url("YQ==").unwrap(), b"a");
+        assert_eq!(decode_base64url("YWI").unwrap(), b"ab");
+        assert_eq!(decode_base64url("YWI=").unwrap(), b"ab");
+        assert_eq!(decode_base64url("YWJj").unwrap(), b"abc");
+        assert_eq!(decode_base64url("-_8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode_base64url("").unwrap(), b"");
+        assert_eq!(decode_base64url(CURL_SETTINGS).unwrap(), [0, 3, 0, 0, 0, 100, 0, 4, 0, 160, 0, 0, 0, 2, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_invalid_base64url() {
+        assert!(decode_base64url("+/8").is_none());
+        assert!(decode_base64url("YW I").is_none());
+        assert!(decode_base64url("YQ=\n").is_none());
+    }
+
+    #[test]
+    fn declines_bad_settings() {
+        assert!(h2c_settings(&upgrade_request(&[])).is_some());
+        // not a whole number of settings
+        let mut req = upgrade_request(&[]);
+        req.headers_mut().insert("http2-settings", HeaderValue::from_static("AAMAAABk"));
+        assert!(h2c_settings(&req).is_some());
+        req.headers_mut().insert("http2-settings", HeaderValue::from_static("AAMAAAB"));
+        assert!(h2c_settings(&req).is_none());
+        req.headers_mut().insert("http2-settings", HeaderValue::from_static("AAMA*ABk"));
+        assert!(h2c_settings(&req).is_none());
+    }
+
+    #[tokio::test]
+    async fn connection_start_merges_settings_and_adds_first_stream() {
+        let mut client = BytesMut::new();
+        client.put_slice(PREFACE);
+        put_frame(&mut client, FRAME_SETTINGS, 0, 0, &[0, 4, 0, 0, 0xff, 0xff]);
+        client.put_slice(b"next frames");
+        let mut client = &client[..];
+
+        let settings = decode_base64url(CURL_SETTINGS).unwrap();
+        let start = connection_start(&mut client, &settings, b"block").await.unwrap();
+
+        assert_eq!(client, b"next frames");
+        assert!(start.starts_with(PREFACE));
+        let mut merged = settings.clone();
+        merged.extend([0, 4, 0, 0, 0xff, 0xff]);
+        assert_eq!(frames(&start[PREFACE.len()..]), vec![
+            (FRAME_SETTINGS, 0, 0, merged),
+            (FRAME_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS, 1, b"block".to_vec()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn connection_start_requires_preface_and_settings() {
+        let mut client = &b"GET / HTTP/1.1\r\n\r\n and some more bytes"[..];
+        assert_eq!(connection_start(&mut client, &[], b"").await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut frame = BytesMut::from(PREFACE);
+        put_frame(&mut frame, FRAME_HEADERS, FLAG_END_HEADERS, 1, b"block");
+        assert_eq!(connection_start(&mut &frame[..], &[], b"").await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn upgrade_request_is_served_as_first_stream() {
+        let long = "l".repeat(20000);
+        let medium = "m".repeat(200);
+        let req = upgrade_request(&[("x-long", long.clone()), ("x-medium", medium.clone()), ("te", String::from("trailers"))]);
+        let settings = h2c_settings(&req).unwrap();
+        let first_stream = header_block(&req);
+
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let mut client_start = BytesMut::from(PREFACE);
+        put_frame(&mut client_start, FRAME_SETTINGS, 0, 0, &[]);
+        client.write_all(&client_start).await.unwrap();
+
+        let (received, served) = oneshot::channel();
+        let mut received = Some(received);
+        let service = service_fn(move |req: Request<Body>| {
+            if let Some(received) = received.take() {
+                let _ = received.send(req);
+            }
+            ready(Ok::<_, Infallible>(Response::new(Body::empty())))
+        });
+        tokio::spawn(async move {
+            let mut server = server;
+            let start = connection_start(&mut server, &settings, &first_stream).await.unwrap();
+            let _ = Http::new().http2_only(true).serve_connection(Rewind { prefix: start, inner: server }, service).await;
+        });
+
+        let served = served.await.unwrap();
+        assert_eq!(served.version(), Version::HTTP_2);
+        assert_eq!(served.method(), "GET");
+        assert_eq!(served.uri(), "http://app.default/path?query=1");
+        assert_eq!(served.headers()["x-long"], long.as_str());
+        assert_eq!(served.headers()["x-medium"], medium.as_str());
+        assert_eq!(served.headers()["te"], "trailers");
+        for name in ["connection", "upgrade", "http2-settings", "host"] {
+            assert!(!served.headers().contains_key(name), "{} was not removed", name);
+        }
+    }
+}
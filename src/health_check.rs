@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hyper::client::conn::Builder;
+use hyper::{Request, StatusCode};
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::port_forward::PortStream;
+use crate::target_host::TargetHost;
+
+const GRPC_HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+/// `HealthCheckResponse.ServingStatus.SERVING`
+const GRPC_SERVING: u64 = 1;
+
+/// How a pod of the target is asked whether it is healthy.
+#[derive(Debug, Clone)]
+pub enum HealthProbe {
+    /// GET of the path, 2xx and 3xx mean healthy
+    Http(String),
+    /// `grpc.health.v1.Health/Check` of the service (empty means the whole server)
+    Grpc(String),
+}
+
+impl HealthProbe {
+    /// `/healthz`, `grpc` or `grpc:my.package.Service`
+    pub fn parse(probe: &str) -> Option<HealthProbe> {
+        match probe.split_once(':') {
+            _ if probe.starts_with('/') => Some(HealthProbe::Http(String::from(probe))),
+            _ if probe == "grpc" => Some(HealthProbe::Grpc(String::new())),
+            Some(("grpc", service)) => Some(HealthProbe::Grpc(String::from(service))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthCheckConfig {
+    pub probes: HashMap<TargetHost, HealthProbe>,
+    pub interval: Duration,
+    /// probe of a single pod (including opening the port-forward) fails after that long
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Error)]
+pub enum ProbeError {
+    #[error("unable to establish http connection: {0}")]
+    Handshake(#[source] hyper::Error),
+    #[error("request failed: {0}")]
+    Request(#[source] hyper::Error),
+    #[error("responded with {0}")]
+    Status(StatusCode),
+    #[error("responded with grpc-status {0}")]
+    GrpcStatus(String),
+    #[error("responded with serving status {0}")]
+    NotServing(u64),
+    #[error("malformed grpc health check response")]
+    Malformed,
+}
+
+/// Pods which failed their latest probe, per target.
+#[derive(Default)]
+pub struct HealthStatus {
+    unhealthy: Mutex<HashMap<TargetHost, HashSet<String>>>,
+}
+
+impl HealthStatus {
+    /// Replaces the unhealthy pods of the target with the outcome of the latest round of probes.
+    pub fn update(&self, target: &TargetHost, unhealthy: HashSet<String>) {
+        self.unhealthy.lock().insert(target.clone(), unhealthy);
+    }
+
+    /// Whether the pod failed the probe of the target, probes of other targets (e.g. other ports
+    /// of the same service) don't count. Target without port matches probes of any port.
+    /// Pods which were never probed (e.g. they've just started) are considered healthy.
+    pub fn is_unhealthy(&self, target: &TargetHost, pod: &str) -> bool {
+        self.unhealthy.lock().iter()
+            .filter(|(probed, _)| is_probe_of(probed, target))
+            .any(|(_, unhealthy)| unhealthy.contains(pod))
+    }
+}
+
+fn is_probe_of(probed: &TargetHost, target: &TargetHost) -> bool {
+    probed.application_name == target.application_name
+        && probed.namespace == target.namespace
+        && (probed.port.is_none() || target.port.is_none() || probed.port == target.port)
+}
+
+/// Sends the probe over given (port-forwarded) stream.
+pub async fn probe(stream: Box<dyn PortStream>, host: &str, probe: &HealthProbe, http2: bool) -> Result<(), ProbeError> {
+    match probe {
+        HealthProbe::Http(path) => probe_http(stream, host, path, http2).await,
+        HealthProbe::Grpc(service) => probe_grpc(stream, host, service).await,
+    }
+}
+
+async fn probe_http(stream: Box<dyn PortStream>, host: &str, path: &str, http2: bool) -> Result<(), ProbeError> {
+    let (mut sender, connection) = Builder::new().http2_only(http2).handshake(stream).await.map_err(ProbeError::Handshake)?;
+    tokio::spawn(connection);
+
+    let uri = if http2 { format!("http://{}{}", host, path) } else { String::from(path) };
+    let request = Request::get(uri)
+        .header(hyper::header::HOST, host)
+        .body(hyper::Body::empty())
+        .unwrap();
+    let response = sender.send_request(request).await.map_err(ProbeError::Request)?;
+
+    let status = response.status();
+    if status.is_success() || status.is_redirection() {
+        Ok(())
+    } else {
+        Err(ProbeError::Status(status))
+    }
+}
+
+async fn probe_grpc(stream: Box<dyn PortStream>, host: &str, service: &str) -> Result<(), ProbeError> {
+    let (mut sender, connection) = Builder::new().http2_only(true).handshake(stream).await.map_err(ProbeError::Handshake)?;
+    tokio::spawn(connection);
+
+    let request = Request::post(format!("http://{}{}", host, GRPC_HEALTH_CHECK_PATH))
+        .header(hyper::header::CONTENT_TYPE, "application/grpc")
+        .header(hyper::header::TE, "trailers")
+        .body(hyper::Body::from(health_check_request(service)))
+        .unwrap();
+    let response = sender.send_request(request).await.map_err(ProbeError::Request)?;
+    if response.status() != StatusCode::OK {
+        return Err(ProbeError::Status(response.status()));
+    }
+
+    let headers = response.headers().clone();
+    let mut body = response.into_body();
+    let message = hyper::body::to_bytes(&mut body).await.map_err(ProbeError::Request)?;
+    let trailers = http_body::Body::trailers(&mut body).await.map_err(ProbeError::Request)?;
+
+    // trailers-only responses carry grpc-status in headers
+    let grpc_status = trailers.as_ref()
+        .and_then(|trailers| trailers.get("grpc-status"))
+        .or_else(|| headers.get("grpc-status"))
+        .and_then(|status| status.to_str().ok())
+        .unwrap_or("");
+    if grpc_status != "0" {
+        return Err(ProbeError::GrpcStatus(String::from(grpc_status)));
+    }
+
+    match serving_status(message) {
+        Some(GRPC_SERVING) => Ok(()),
+        Some(status) => Err(ProbeError::NotServing(status)),
+        None => Err(ProbeError::Malformed),
+    }
+}
+
+/// Length-prefixed `HealthCheckRequest { string service = 1; }`.
+fn health_check_request(service: &str) -> Bytes {
+    let mut message = BytesMut::new();
+    if !service.is_empty() {
+        message.put_u8(0x0a);
+        put_varint(&mut message, service.len() as u64);
+        message.put_slice(service.as_bytes());
+    }
+
+    let mut frame = BytesMut::with_capacity(5 + message.len());
+    frame.put_u8(0);
+    frame.put_u32(message.len() as u32);
+    frame.put(message);
+    frame.freeze()
+}
+
+/// Reads `ServingStatus status = 1` out of length-prefixed `HealthCheckResponse`,
+/// missing field means the default `UNKNOWN` (0).
+fn serving_status(mut frame: Bytes) -> Option<u64> {
+    if frame.remaining() < 5 || frame.get_u8() != 0 {
+        return None;
+    }
+    let length = frame.get_u32() as usize;
+    if frame.remaining() < length {
+        return None;
+    }
+
+    let mut message = frame.split_to(length);
+    let mut status = 0;
+    while message.has_remaining() {
+        let key = get_varint(&mut message)?;
+        match key & 0x7 {
+            0 => {
+                let value = get_varint(&mut message)?;
+                if key >> 3 == 1 {
+                    status = value;
+                }
+            }
+            2 => {
+                let length = get_varint(&mut message)? as usize;
+                if message.remaining() < length {
+                    return None;
+                }
+                message.advance(length);
+            }
+            _ => return None,
+        }
+    }
+    Some(status)
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut Bytes) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            return None;
+        }
+        let byte = buf.get_u8();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(port: Option<u16>) -> TargetHost {
+        TargetHost { application_name: String::from("my-app"), namespace: String::from("default"), port }
+    }
+
+    #[test]
+    fn unhealthy_only_for_probed_target() {
+        let status = HealthStatus::default();
+        status.update(&target(Some(9090)), HashSet::from([String::from("pod-1")]));
+
+        assert!(status.is_unhealthy(&target(Some(9090)), "pod-1"));
+        assert!(status.is_unhealthy(&target(None), "pod-1"));
+        assert!(!status.is_unhealthy(&target(Some(8080)), "pod-1"));
+        assert!(!status.is_unhealthy(&target(Some(9090)), "pod-2"));
+
+        let other = TargetHost { application_name: String::from("other-app"), ..target(Some(9090)) };
+        assert!(!status.is_unhealthy(&other, "pod-1"));
+    }
+
+    fn response(message: &[u8]) -> Bytes {
+        let mut frame = BytesMut::from(&[0][..]);
+        frame.put_u32(message.len() as u32);
+        frame.put_slice(message);
+        frame.freeze()
+    }
+
+    #[test]
+    fn encodes_health_check_request() {
+        assert_eq!(&health_check_request("")[..], [0, 0, 0, 0, 0]);
+        assert_eq!(&health_check_request("my.Service")[..], b"\x00\x00\x00\x00\x0c\x0a\x0amy.Service");
+
+        let long = "s".repeat(200);
+        let request = health_check_request(&long);
+        assert_eq!(&request[..8], [0, 0, 0, 0, 203, 0x0a, 0xc8, 0x01]);
+        assert_eq!(&request[8..], long.as_bytes());
+    }
+
+    #[test]
+    fn reads_serving_status() {
+        assert_eq!(serving_status(response(&[0x08, 0x01])), Some(GRPC_SERVING));
+        assert_eq!(serving_status(response(&[0x08, 0x02])), Some(2));
+        // default value is not sent
+        assert_eq!(serving_status(response(&[])), Some(0));
+    }
+
+    #[test]
+    fn skips_unknown_fields() {
+        // string field 2, varint field 3 (multi-byte), then the status
+        let message = [0x12, 0x03, b'a', b'b', b'c', 0x18, 0xac, 0x02, 0x08, 0x01];
+        assert_eq!(serving_status(response(&message)), Some(GRPC_SERVING));
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        // too short for the prefix, compressed, length beyond the frame
+        assert_eq!(serving_status(Bytes::from_static(&[0, 0, 0])), None);
+        assert_eq!(serving_status(Bytes::from_static(&[1, 0, 0, 0, 2, 0x08, 0x01])), None);
+        assert_eq!(serving_status(Bytes::from_static(&[0, 0, 0, 0, 3, 0x08, 0x01])), None);
+        // truncated varint, truncated string, unsupported wire type (fixed64)
+        assert_eq!(serving_status(response(&[0x08, 0x81])), None);
+        assert_eq!(serving_status(response(&[0x12, 0x05, b'a'])), None);
+        assert_eq!(serving_status(response(&[0x09, 0, 0, 0, 0, 0, 0, 0, 0])), None);
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = BytesMut::new();
+            put_varint(&mut buf, value);
+            assert_eq!(get_varint(&mut buf.freeze()), Some(value));
+        }
+
+        let mut encoded = BytesMut::new();
+        put_varint(&mut encoded, 300);
+        assert_eq!(&encoded[..], [0xac, 0x02]);
+        // more than 10 bytes
+        assert_eq!(get_varint(&mut Bytes::from_static(&[0xff; 11])), None);
+    }
+
+    #[test]
+    fn probe_without_port_applies_to_every_port() {
+        let status = HealthStatus::default();
+        status.update(&target(None), HashSet::from([String::from("pod-1")]));
+
+        assert!(status.is_unhealthy(&target(Some(8080)), "pod-1"));
+        assert!(status.is_unhealthy(&target(None), "pod-1"));
+    }
+}
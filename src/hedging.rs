@@ -0,0 +1,52 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use parking_lot::Mutex;
+
+use crate::target_host::TargetHost;
+
+/// Latencies of that many most recent responses are kept per target.
+const LATENCY_WINDOW: usize = 128;
+/// Requests are not hedged until the percentile can be told from that many responses.
+const MIN_SAMPLES: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct HedgePolicy {
+    /// GET which hasn't been answered within this percentile of recent latencies is sent again, e.g. 95.0
+    pub percentile: f64,
+    /// hedged request is never sent sooner than that
+    pub min_delay: Duration,
+}
+
+/// Recent response latencies of every target.
+pub struct Latencies {
+    samples: Mutex<HashMap<TargetHost, VecDeque<Duration>>>,
+}
+
+impl Latencies {
+    pub fn new() -> Latencies {
+        Latencies { samples: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn record(&self, target: &TargetHost, latency: Duration) {
+        let mut samples = self.samples.lock();
+        let samples = samples.entry(target.clone()).or_default();
+        if samples.len() >= LATENCY_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    /// How long to wait for the response before hedging, `None` when there are not enough samples yet.
+    pub fn hedge_delay(&self, target: &TargetHost, policy: &HedgePolicy) -> Option<Duration> {
+        let mut sorted: Vec<Duration> = self.samples.lock()
+            .get(target)
+            .filter(|samples| samples.len() >= MIN_SAMPLES)?
+            .iter()
+            .copied()
+            .collect();
+        sorted.sort();
+
+        let rank = (policy.percentile.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+        Some(sorted[rank].max(policy.min_delay))
+    }
+}
@@ -1,24 +1,227 @@
+use std::collections::{HashMap, HashSet};
 use std::future::ready;
+use std::path::PathBuf;
+use std::time::Duration;
 use std::{convert::Infallible, net::SocketAddr};
 use clap::Parser;
-use hyper::{service::make_service_fn, Server};
+use hyper::{service::make_service_fn, Server, StatusCode};
 use kube::client::ConfigExt;
 use kube::config::{KubeConfigOptions, Kubeconfig};
 use kube::{Client, Config};
 use print_ascii::print_rocket_std_output;
 use tower::ServiceBuilder;
 use std::fmt::Debug;
-use crate::forwarding_service::{LogLayer, RequestHandlingService};
+use crate::forwarding_service::{BalancingStrategy, Forwarder, ForwardingConfig, LogLayer, RequestHandlingService};
+use crate::h2c::H2cUpgradeLayer;
+use crate::target_host::{TargetHost, DEFAULT_CLUSTER_DOMAIN};
+use crate::tcp_forward::TcpForward;
+use crate::health_check::{HealthCheckConfig, HealthProbe};
+use crate::hedging::HedgePolicy;
+use crate::outlier::OutlierPolicy;
+use crate::proxy::ExternalHosts;
+use crate::reply_body::SpillConfig;
+use crate::retry::RetryPolicy;
+use crate::timeouts::Timeouts;
+use crate::upstream_pool::PoolConfig;
 
 mod print_ascii;
 mod forwarding_service;
 mod reply_body;
+mod discovery;
+mod upstream_pool;
+mod port_forward;
+mod retry;
+mod upstream_body;
+mod timeouts;
+mod forward_error;
+mod diagnostics;
+mod hedging;
+mod outlier;
+mod health_check;
+mod upgrade;
+mod websocket;
+mod h2c;
+mod tcp_forward;
+mod proxy;
+mod target_host;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     #[clap(short, long)]
     kube_config: String,
+
+    /// cluster domain accepted in fully qualified hosts (app.namespace.svc.<cluster-domain>)
+    #[clap(long, default_value = DEFAULT_CLUSTER_DOMAIN)]
+    cluster_domain: String,
+
+    /// namespace for hosts without one, defaults to the namespace of the kubeconfig context
+    #[clap(short, long)]
+    namespace: Option<String>,
+
+    /// how new upstream connections are spread across ready pods
+    #[clap(long, value_enum, default_value = "round-robin")]
+    balancing: BalancingStrategy,
+
+    /// seconds after which idle upstream connections (and their port-forwards) are closed
+    #[clap(long, default_value = "90")]
+    pool_idle_timeout: u64,
+
+    /// maximum number of idle upstream connections kept open
+    #[clap(long, default_value = "64")]
+    pool_max_idle: usize,
+
+    /// maximum number of concurrent upstream connections per target, further requests wait for a free one
+    #[clap(long, default_value = "32")]
+    pool_max_per_target: usize,
+
+    /// maximum number of retries of a single request
+    #[clap(long, default_value = "10")]
+    max_retries: usize,
+
+    /// retry also requests with methods which are not idempotent (POST, PATCH) after they reached the pod
+    #[clap(long)]
+    retry_non_idempotent: bool,
+
+    /// response statuses which should be retried, e.g. 502,503
+    #[clap(long, use_value_delimiter = true)]
+    retry_on_status: Vec<u16>,
+
+    /// backoff before the first retry in milliseconds, doubled (with jitter) for subsequent retries
+    #[clap(long, default_value = "100")]
+    retry_backoff_ms: u64,
+
+    /// maximum backoff between retries in milliseconds
+    #[clap(long, default_value = "2000")]
+    retry_max_backoff_ms: u64,
+
+    /// no retries are started after that many milliseconds since the request was received
+    #[clap(long, default_value = "10000")]
+    retry_deadline_ms: u64,
+
+    /// retries allowed per original request to a target (0.2 means 20% more requests), further retries fail fast
+    #[clap(long, default_value = "0.2")]
+    retry_budget_ratio: f32,
+
+    /// retries per second allowed for a target regardless of the ratio
+    #[clap(long, default_value = "10")]
+    retry_budget_min_per_sec: u32,
+
+    /// request bodies up to that many bytes are buffered so they can be retried, larger ones are streamed without retries
+    #[clap(long, default_value = "65536")]
+    max_replay_body_bytes: usize,
+
+    /// buffer request bodies larger than --max-replay-body-bytes in temporary files, so they can be retried too
+    #[clap(long)]
+    spill_to_disk: bool,
+
+    /// directory of the temporary files, system temp directory by default
+    #[clap(long)]
+    spill_dir: Option<PathBuf>,
+
+    /// maximum number of bytes of a single request body written to disk
+    #[clap(long, default_value = "67108864")]
+    max_spill_bytes: usize,
+
+    /// timeout of the whole request (including retries) in milliseconds
+    #[clap(long, default_value = "60000")]
+    request_timeout_ms: u64,
+
+    /// timeout of a single forwarding attempt (until the response head is received) in milliseconds
+    #[clap(long, default_value = "30000")]
+    attempt_timeout_ms: u64,
+
+    /// timeout of finding the service and its pods in milliseconds
+    #[clap(long, default_value = "10000")]
+    discovery_timeout_ms: u64,
+
+    /// timeout of opening the port-forward in milliseconds
+    #[clap(long, default_value = "10000")]
+    port_forward_timeout_ms: u64,
+
+    /// hedge GETs: when there is no response within this percentile of recent latencies (e.g. 95),
+    /// the request is sent to another pod too and the first response wins
+    #[clap(long)]
+    hedge_percentile: Option<f64>,
+
+    /// hedged request is never sent sooner than that many milliseconds
+    #[clap(long, default_value = "10")]
+    hedge_min_delay_ms: u64,
+
+    /// when a target has no ready pods, requests wait that many milliseconds for one
+    /// (e.g. during rollouts) instead of failing right away
+    #[clap(long)]
+    wait_for_ready_ms: Option<u64>,
+
+    /// pod is left out of selection after that many failures in a row (port-forward errors,
+    /// broken connections, 5xx responses), 0 disables it
+    #[clap(long, default_value = "5")]
+    outlier_consecutive_failures: u32,
+
+    /// first ejection of a pod lasts that many milliseconds, every next one longer by the same amount
+    #[clap(long, default_value = "30000")]
+    outlier_ejection_ms: u64,
+
+    /// maximum ejection of a pod in milliseconds
+    #[clap(long, default_value = "300000")]
+    outlier_max_ejection_ms: u64,
+
+    /// probe pods of the target in the background, e.g. `my-app.default=/healthz`, `my-app.default=grpc`
+    /// or `my-app.default:9090=grpc:my.package.Service`, pods failing the probe are not used
+    #[clap(long, multiple_occurrences = true)]
+    health_check: Vec<String>,
+
+    /// health checks are performed that often, in milliseconds
+    #[clap(long, default_value = "10000")]
+    health_check_interval_ms: u64,
+
+    /// health check of a single pod fails after that many milliseconds
+    #[clap(long, default_value = "2000")]
+    health_check_timeout_ms: u64,
+
+    /// speak HTTP/2 (h2c) to pods of the target, e.g. `grpc-app.default`; also used for Service ports
+    /// with `appProtocol` h2c, kubernetes.io/h2c, http2 or grpc
+    #[clap(long, multiple_occurrences = true)]
+    http2_target: Vec<String>,
+
+    /// forward raw TCP connections of a local port to a service port, e.g. `15432=postgres.db:5432`
+    #[clap(long, multiple_occurrences = true)]
+    tcp: Vec<String>,
+
+    /// port of the HTTP server (also usable as HTTP proxy, e.g. `HTTP_PROXY=http://127.0.0.1:8080`)
+    #[clap(long, default_value = "80")]
+    port: u16,
+
+    /// what happens to proxy requests (absolute-form or CONNECT) for hosts which are not services of the cluster
+    #[clap(long, value_enum, default_value = "reject")]
+    external_hosts: ExternalHosts,
+}
+
+fn health_probes(health_checks: &[String], cluster_domain: &str, default_namespace: &str) -> HashMap<TargetHost, HealthProbe> {
+    health_checks.iter()
+        .map(|health_check| {
+            let (target, probe) = health_check.split_once('=')
+                .unwrap_or_else(|| panic!("{} is not a valid health check, expected <host>=<probe>", health_check));
+            let target = TargetHost::parse(target, cluster_domain, default_namespace)
+                .unwrap_or_else(|err| panic!("{} is not a valid health check: {}", health_check, err));
+            let probe = HealthProbe::parse(probe)
+                .unwrap_or_else(|| panic!("{} is not a valid health check probe, expected path, grpc or grpc:<service>", probe));
+            (target, probe)
+        })
+        .collect()
+}
+
+fn http2_targets(hosts: &[String], cluster_domain: &str, default_namespace: &str) -> HashSet<TargetHost> {
+    hosts.iter()
+        .map(|host| TargetHost::parse(host, cluster_domain, default_namespace)
+            .unwrap_or_else(|err| panic!("{} is not a valid http2 target: {}", host, err)))
+        .collect()
+}
+
+fn retry_statuses(codes: &[u16]) -> Vec<StatusCode> {
+    codes.iter()
+        .map(|code| StatusCode::from_u16(*code).unwrap_or_else(|_| panic!("{} is not a valid http status", code)))
+        .collect()
 }
 
 #[tokio::main]
@@ -45,17 +248,80 @@ async fn main() {
         .option_layer(config.auth_layer().unwrap())
         .service(hyper::Client::builder().build(https));
             
-    let client = Client::new(service, "there-is-no-default-namespace");
+    let default_namespace = args.namespace.clone().unwrap_or_else(|| config.default_namespace.clone());
+    log::info!("hosts without namespace will be forwarded to namespace {}", default_namespace);
+
+    let client = Client::new(service, default_namespace.clone());
+
+    let health_probes = health_probes(&args.health_check, &args.cluster_domain, &default_namespace);
+    let http2_targets = http2_targets(&args.http2_target, &args.cluster_domain, &default_namespace);
+    let tcp_forwards: Vec<TcpForward> = args.tcp.iter()
+        .map(|forward| TcpForward::parse(forward, &args.cluster_domain, &default_namespace)
+            .unwrap_or_else(|err| panic!("{} is not a valid tcp forward, expected <local port>=<host>:<port>: {}", forward, err)))
+        .collect();
+    let forwarder = Forwarder::new(client, ForwardingConfig {
+        cluster: config.cluster_url.to_string(),
+        cluster_domain: args.cluster_domain.clone(),
+        default_namespace,
+        balancing: args.balancing,
+        pool: PoolConfig {
+            idle_timeout: Duration::from_secs(args.pool_idle_timeout),
+            max_idle: args.pool_max_idle,
+            max_per_target: args.pool_max_per_target,
+        },
+        retry: RetryPolicy {
+            max_retries: args.max_retries,
+            retry_non_idempotent: args.retry_non_idempotent,
+            retry_statuses: retry_statuses(&args.retry_on_status),
+            initial_backoff: Duration::from_millis(args.retry_backoff_ms),
+            max_backoff: Duration::from_millis(args.retry_max_backoff_ms),
+            deadline: Duration::from_millis(args.retry_deadline_ms),
+            budget_ratio: args.retry_budget_ratio,
+            budget_min_per_sec: args.retry_budget_min_per_sec,
+        },
+        max_replay_body_bytes: args.max_replay_body_bytes,
+        spill: args.spill_to_disk.then(|| SpillConfig {
+            dir: args.spill_dir.clone().unwrap_or_else(std::env::temp_dir),
+            max_disk_bytes: args.max_spill_bytes,
+        }),
+        timeouts: Timeouts {
+            request: Duration::from_millis(args.request_timeout_ms),
+            attempt: Duration::from_millis(args.attempt_timeout_ms),
+            discovery: Duration::from_millis(args.discovery_timeout_ms),
+            port_forward: Duration::from_millis(args.port_forward_timeout_ms),
+        },
+        hedge: args.hedge_percentile.map(|percentile| HedgePolicy {
+            percentile,
+            min_delay: Duration::from_millis(args.hedge_min_delay_ms),
+        }),
+        wait_for_ready: args.wait_for_ready_ms.map(Duration::from_millis),
+        outlier: OutlierPolicy {
+            consecutive_failures: args.outlier_consecutive_failures,
+            base_ejection: Duration::from_millis(args.outlier_ejection_ms),
+            max_ejection: Duration::from_millis(args.outlier_max_ejection_ms),
+        },
+        health_check: (!health_probes.is_empty()).then(|| HealthCheckConfig {
+            probes: health_probes,
+            interval: Duration::from_millis(args.health_check_interval_ms),
+            timeout: Duration::from_millis(args.health_check_timeout_ms),
+        }),
+        http2_targets,
+        external_hosts: args.external_hosts,
+    });
+
+    for tcp_forward in tcp_forwards {
+        tokio::spawn(tcp_forward::serve(forwarder.clone(), tcp_forward));
+    }
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
     print_rocket_std_output();
 
     let make_svc = make_service_fn(move |_conn: &hyper::server::conn::AddrStream| {
         
-        let client = client.clone();
-        let service = RequestHandlingService::new(client);
+        let service = RequestHandlingService::new(forwarder.clone());
 
         let svc = ServiceBuilder::new()
+        .layer(H2cUpgradeLayer)
         .layer(LogLayer)
         .service(service);
 
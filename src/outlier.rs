@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+
+#[derive(Debug, Clone)]
+pub struct OutlierPolicy {
+    /// pod is ejected after that many failures in a row, 0 disables ejecting
+    pub consecutive_failures: u32,
+    /// first ejection lasts that long, every next one of the same pod longer by the same amount
+    pub base_ejection: Duration,
+    pub max_ejection: Duration,
+}
+
+/// Passive outlier detection (Envoy style). Outcomes of forwarded requests are recorded per pod,
+/// and pods which keep failing (port-forward errors, broken connections, 5xx) are left out of
+/// selection for a while.
+pub struct OutlierDetector {
+    policy: OutlierPolicy,
+    pods: Mutex<HashMap<String, PodHealth>>,
+}
+
+#[derive(Default)]
+struct PodHealth {
+    consecutive_failures: u32,
+    /// how many times the pod was ejected since it last succeeded
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl OutlierDetector {
+    pub fn new(policy: OutlierPolicy) -> OutlierDetector {
+        OutlierDetector { policy, pods: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn record_success(&self, pod: &str) {
+        let mut pods = self.pods.lock();
+        let still_ejected = pods.get(pod)
+            .and_then(|health| health.ejected_until)
+            .map(|ejected_until| ejected_until > Instant::now())
+            .unwrap_or(false);
+
+        // pods which are fine are not tracked at all
+        if !still_ejected {
+            pods.remove(pod);
+        }
+    }
+
+    pub fn record_failure(&self, pod: &str) {
+        if self.policy.consecutive_failures == 0 {
+            return;
+        }
+
+        let mut pods = self.pods.lock();
+        let health = pods.entry(String::from(pod)).or_default();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures < self.policy.consecutive_failures {
+            return;
+        }
+
+        health.ejections += 1;
+        health.consecutive_failures = 0;
+        let ejection = self.policy.base_ejection
+            .saturating_mul(health.ejections)
+            .min(self.policy.max_ejection);
+        health.ejected_until = Some(Instant::now() + ejection);
+        log::warn!("pod {} failed {} times in a row, ejecting it for {}ms", pod, self.policy.consecutive_failures, ejection.as_millis());
+    }
+
+    pub fn is_ejected(&self, pod: &str) -> bool {
+        self.pods.lock()
+            .get(pod)
+            .and_then(|health| health.ejected_until)
+            .map(|ejected_until| ejected_until > Instant::now())
+            .unwrap_or(false)
+    }
+}
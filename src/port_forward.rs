@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::Portforwarder;
+use kube::{Api, ResourceExt};
+use parking_lot::Mutex;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Streams opened ahead of time are handed out only for that long,
+/// applications tend to close connections which didn't send anything.
+const SPARE_STREAM_TTL: Duration = Duration::from_secs(5);
+
+static SESSION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Error)]
+pub enum PortForwardError {
+    #[error("unable to obtain port-forwarder: {0}")]
+    Open(#[from] kube::Error),
+    #[error("unable to obtain stream for port {0}")]
+    NoStream(u16),
+}
+
+pub trait PortStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PortStream for T {}
+
+/// Port-forward WebSocket sessions, at most one live session per pod.
+///
+/// The WebSocket port-forward protocol fixes the ports when the session is opened and carries a single
+/// stream per port, and the kubelet connects to all of them right away. So a session is opened for
+/// the ports streams of the pod were asked for so far (by pooled connections, health probes...) - the
+/// requested stream is returned and the other ones are kept as spares for the next stream to their
+/// port. A port whose spare is taken (or expired) needs a new session. When the WebSocket drops while
+/// the pod is in use, the session is re-opened right away, so the streams replacing the dropped ones
+/// don't wait for the api server.
+pub struct PortForwardSessions {
+    pods: Mutex<HashMap<String, PodSessions>>,
+}
+
+struct PodSessions {
+    pods: Api<Pod>,
+    name: String,
+    /// ports streams were asked for, in the order of the first request
+    ports: Vec<u16>,
+    last_used: Instant,
+    session: Option<Session>,
+}
+
+struct Session {
+    id: u64,
+    opened: Instant,
+    spares: HashMap<u16, Box<dyn PortStream>>,
+}
+
+impl PortForwardSessions {
+    pub fn new() -> Arc<PortForwardSessions> {
+        Arc::new(PortForwardSessions { pods: Mutex::new(HashMap::new()) })
+    }
+
+    pub async fn stream(self: &Arc<Self>, pods: &Api<Pod>, pod: &Pod, port: u16) -> Result<Box<dyn PortStream>, PortForwardError> {
+        let key = pod.uid().unwrap_or_else(|| pod.name_any());
+        let ports = {
+            let mut all = self.pods.lock();
+            let sessions = all.entry(key.clone()).or_insert_with(|| PodSessions {
+                pods: pods.clone(),
+                name: pod.name_any(),
+                ports: Vec::new(),
+                last_used: Instant::now(),
+                session: None,
+            });
+            sessions.last_used = Instant::now();
+            if !sessions.ports.contains(&port) {
+                sessions.ports.push(port);
+            }
+            if let Some(stream) = sessions.take_spare(port) {
+                log::info!("reusing port-forward session to pod {} for port {}", sessions.name, port);
+                return Ok(stream);
+            }
+
+            // requested port first, the others are opened as spares
+            let mut ports = vec![port];
+            ports.extend(sessions.ports.iter().filter(|other| **other != port));
+            ports
+        };
+
+        let (id, mut streams) = self.open(pods, &pod.name_any(), &key, &ports).await?;
+        let stream = streams.remove(&port).ok_or(PortForwardError::NoStream(port))?;
+        self.install(&key, id, streams);
+        Ok(stream)
+    }
+
+    /// Opens the WebSocket and takes all of its streams, `watch` re-opens it once it drops.
+    async fn open(self: &Arc<Self>, pods: &Api<Pod>, name: &str, key: &str, ports: &[u16]) -> Result<(u64, HashMap<u16, Box<dyn PortStream>>), PortForwardError> {
+        let (pf, streams) = connect(pods, name, ports).await?;
+        let id = SESSION_COUNTER.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(watch(Arc::downgrade(self), String::from(key), id, pf));
+        tokio::spawn(expire(Arc::downgrade(self), String::from(key), id));
+        Ok((id, streams))
+    }
+
+    /// Forgets the session whose WebSocket is gone, returns what is needed to re-open it
+    /// when the pod is still in use and no other session replaced it.
+    fn dropped(&self, key: &str, id: u64) -> Option<(Api<Pod>, String, Vec<u16>)> {
+        let mut all = self.pods.lock();
+        let pod = all.get_mut(key)?;
+        if pod.session.as_ref().is_some_and(|session| session.id == id) {
+            pod.session = None;
+        }
+        if pod.session.is_some() {
+            return None;
+        }
+        if pod.last_used.elapsed() >= SPARE_STREAM_TTL {
+            all.remove(key);
+            return None;
+        }
+        Some((pod.pods.clone(), pod.name.clone(), pod.ports.clone()))
+    }
+
+    /// Pod which can't be port-forwarded anymore (probably deleted), unless a stream opened another session meanwhile.
+    fn forget(&self, key: &str) {
+        let mut all = self.pods.lock();
+        if all.get(key).is_some_and(|pod| pod.session.is_none()) {
+            all.remove(key);
+        }
+    }
+
+    /// Keeps the streams of a newly opened session as spares, spares of the previous one are dropped.
+    fn install(&self, key: &str, id: u64, spares: HashMap<u16, Box<dyn PortStream>>) {
+        if let Some(sessions) = self.pods.lock().get_mut(key) {
+            sessions.session = Some(Session { id, opened: Instant::now(), spares });
+        }
+    }
+}
+
+impl PodSessions {
+    fn take_spare(&mut self, port: u16) -> Option<Box<dyn PortStream>> {
+        let session = self.session.as_mut()?;
+        if session.opened.elapsed() >= SPARE_STREAM_TTL {
+            session.spares.clear();
+            return None;
+        }
+        session.spares.remove(&port)
+    }
+}
+
+/// Closes spare streams which were not used in time, so the WebSocket can close once the taken ones are done.
+async fn expire(sessions: Weak<PortForwardSessions>, key: String, id: u64) {
+    tokio::time::sleep(SPARE_STREAM_TTL).await;
+    if let Some(sessions) = sessions.upgrade() {
+        let mut all = sessions.pods.lock();
+        if let Some(session) = all.get_mut(&key).and_then(|sessions| sessions.session.as_mut()).filter(|session| session.id == id) {
+            session.spares.clear();
+        }
+    }
+}
+
+async fn connect(pods: &Api<Pod>, name: &str, ports: &[u16]) -> Result<(Portforwarder, HashMap<u16, Box<dyn PortStream>>), PortForwardError> {
+    let mut pf = pods.portforward(name, ports).await?;
+    let mut streams: HashMap<u16, Box<dyn PortStream>> = HashMap::new();
+    for port in ports.iter().copied() {
+        if let Some(stream) = pf.take_stream(port) {
+            streams.insert(port, Box::new(stream));
+        }
+        // error receiver has to be kept, the WebSocket is closed when the error can't be delivered
+        if let Some(errors) = pf.take_error(port) {
+            let name = String::from(name);
+            tokio::spawn(async move {
+                // resolves with a message when the port fails, or with nothing when the WebSocket is gone
+                if let Some(err) = errors.await {
+                    log::info!("port-forward to pod {} port {} failed: {}", name, port, err);
+                }
+            });
+        }
+    }
+    Ok((pf, streams))
+}
+
+/// Waits for the WebSocket of the session to drop - it is opened again (with all ports as spares) when
+/// streams of the pod were asked for recently, otherwise the pod is forgotten until its next stream.
+async fn watch(sessions: Weak<PortForwardSessions>, key: String, mut id: u64, mut pf: Portforwarder) {
+    loop {
+        if let Err(err) = pf.join().await {
+            log::info!("port-forward session {} failed: {}", key, err);
+        }
+
+        let (pods, name, ports) = match sessions.upgrade().and_then(|sessions| sessions.dropped(&key, id)) {
+            Some(reopen) => reopen,
+            None => return,
+        };
+        log::info!("port-forward session to pod {} dropped, re-opening it for ports {:?}", name, ports);
+        let spares = match connect(&pods, &name, &ports).await {
+            Ok((reopened, spares)) => {
+                pf = reopened;
+                spares
+            }
+            Err(err) => {
+                log::info!("unable to re-open port-forward session to pod {}: {}", name, err);
+                if let Some(sessions) = sessions.upgrade() {
+                    sessions.forget(&key);
+                }
+                return;
+            }
+        };
+
+        id = SESSION_COUNTER.fetch_add(1, Ordering::Relaxed);
+        match sessions.upgrade() {
+            Some(sessions) => sessions.install(&key, id, spares),
+            None => return,
+        }
+        tokio::spawn(expire(sessions.clone(), key.clone(), id));
+    }
+}
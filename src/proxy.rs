@@ -0,0 +1,67 @@
+use clap::ValueEnum;
+use hyper::client::conn::Builder;
+use hyper::header::{HeaderValue, HOST, PROXY_AUTHORIZATION};
+use hyper::{HeaderMap, Method, Request, Response, Uri, Version};
+use tokio::net::TcpStream;
+
+use crate::forward_error::ForwardError;
+
+/// What happens to proxy requests for hosts which are not services of the cluster.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExternalHosts {
+    /// 403 is returned
+    Reject,
+    /// request is sent (or tunnel opened) straight from this machine
+    PassThrough,
+}
+
+/// Request sent to us as to a proxy - `CONNECT host:port` or HTTP/1 request with absolute-form uri
+/// (`GET http://my-app.default.svc/path`). HTTP/2 requests carry authority always, so they are not.
+pub fn is_proxy_request<B>(req: &Request<B>) -> bool {
+    req.method() == Method::CONNECT || (req.version() < Version::HTTP_2 && req.uri().authority().is_some())
+}
+
+/// Authority of the uri with the port of its scheme when there is none.
+fn connect_address(uri: &Uri) -> Option<String> {
+    let authority = uri.authority()?;
+    match (authority.port(), uri.scheme_str()) {
+        (Some(_), _) => Some(authority.to_string()),
+        (None, Some("https")) => Some(format!("{}:443", authority)),
+        (None, _) => Some(format!("{}:80", authority)),
+    }
+}
+
+/// Headers meant for the proxy are not passed on, Host is set to the authority of the uri (RFC 9112, 3.2.2).
+pub fn strip_proxy_headers(headers: &mut HeaderMap, authority: &str) {
+    headers.remove(PROXY_AUTHORIZATION);
+    headers.remove("proxy-connection");
+    if let Ok(value) = HeaderValue::from_str(authority) {
+        headers.insert(HOST, value);
+    }
+}
+
+/// Sends the proxy request for a host outside of the cluster over a new connection to that host.
+pub async fn pass_through(mut req: Request<hyper::Body>) -> Result<Response<hyper::Body>, ForwardError> {
+    let host = connect_address(req.uri()).unwrap_or_default();
+    let stream = TcpStream::connect(&host).await
+        .map_err(|source| ForwardError::ExternalConnect { host: host.clone(), source })?;
+    let external = |source| ForwardError::External { host: host.clone(), source };
+    let (mut sender, connection) = Builder::new().handshake(stream).await.map_err(external)?;
+    let moved_host = host.clone();
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            log::info!("[{}] connection closed: {}", moved_host, err);
+        }
+    });
+
+    if let Some(authority) = req.uri().authority().map(|authority| authority.to_string()) {
+        strip_proxy_headers(req.headers_mut(), &authority);
+    }
+    let path_and_query = req.uri().path_and_query().map(|path_and_query| path_and_query.as_str()).unwrap_or("/");
+    if let Ok(uri) = path_and_query.parse() {
+        *req.uri_mut() = uri;
+    }
+    *req.version_mut() = Version::HTTP_11;
+
+    sender.send_request(req).await.map_err(external)
+}
@@ -7,14 +7,31 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use http::HeaderMap;
 use http_body::{Body, SizeHint};
 use std::{collections::VecDeque, io::IoSlice, pin::Pin, sync::Arc, task::Context, task::Poll};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use parking_lot::Mutex;
 use thiserror::Error;
+use tokio::task::JoinHandle;
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
+/// Size of chunks read back from the spill file on replay.
+const SPILL_READ_CHUNK: usize = 64 * 1024;
+/// Queued chunks are written through a buffer of that size.
+const SPILL_WRITE_BUFFER: usize = 64 * 1024;
+
+static SPILL_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug, Error)]
 #[error("replay body discarded after reaching maximum buffered bytes limit")]
 pub struct Capped;
 
+#[derive(Debug, Error)]
+#[error("replay body is still held by a previous request")]
+pub struct InUse;
+
 #[derive(Debug)]
 pub enum Data {
     Initial(Bytes),
@@ -26,12 +43,22 @@ pub struct BufList {
     bufs: VecDeque<Bytes>,
 }
 
+/// Where (and how much of) the body exceeding the in-memory buffer is written.
+#[derive(Clone, Debug)]
+pub struct SpillConfig {
+    pub dir: PathBuf,
+    pub max_disk_bytes: usize,
+}
+
 #[derive(Debug)]
 pub struct ReplayBody<B> {
     state: Option<BodyState<B>>,
     shared: Arc<SharedState<B>>,
     replay_body: bool,
     replay_trailers: bool,
+    /// Has this clone still data to replay from the spill file? And from which offset.
+    replay_spill: bool,
+    spill_offset: u64,
 }
 
 #[derive(Debug)]
@@ -41,6 +68,38 @@ struct BodyState<B> {
     rest: Option<B>,
     is_completed: bool,
     max_bytes: usize,
+    spill: Option<Spill>,
+}
+
+/// Chunks which didn't fit into `memory_limit` bytes of `buf` go to a temporary file.
+/// The file is removed when the body state is dropped, i.e. together with the last clone.
+#[derive(Debug)]
+struct Spill {
+    config: SpillConfig,
+    memory_limit: usize,
+    file: Option<SpillFile>,
+}
+
+/// The file is touched only on blocking threads (`spawn_blocking`), never while polling the body.
+/// Chunks are queued and written in batches, the file is created by the first batch. At most one
+/// operation runs at a time, it owns the file until it is done.
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+    /// bytes spilled so far, including the queued ones
+    len: u64,
+    queued: Vec<Bytes>,
+    file: Option<File>,
+    operation: Option<SpillOperation>,
+    /// an operation failed, the file can't be used anymore
+    broken: bool,
+}
+
+#[derive(Debug)]
+enum SpillOperation {
+    Write(JoinHandle<io::Result<File>>),
+    /// chunk read from the given offset
+    Read(u64, JoinHandle<io::Result<(File, Bytes)>>),
 }
 #[derive(Debug)]
 struct SharedState<B> {
@@ -61,6 +120,178 @@ impl<B> BodyState<B> {
     fn is_capped(&self) -> bool {
         self.max_bytes == 0
     }
+
+    /// Should a chunk of given length be written to the spill file rather than kept in memory?
+    fn should_spill(&self, length: usize) -> bool {
+        match self.spill.as_ref() {
+            Some(spill) => spill.file.is_some() || self.buf.remaining() + length > spill.memory_limit,
+            None => false,
+        }
+    }
+
+    fn spilled_len(&self) -> u64 {
+        self.spill.as_ref()
+            .and_then(|spill| spill.file.as_ref())
+            .map(|file| file.len)
+            .unwrap_or(0)
+    }
+
+    /// Drops everything buffered so far (also from disk), the body can't be replayed anymore.
+    fn discard(&mut self) {
+        self.max_bytes = 0;
+        if self.buf.has_remaining() {
+            self.buf = Default::default();
+        }
+        if let Some(spill) = self.spill.as_mut() {
+            spill.file = None;
+        }
+    }
+}
+
+impl Spill {
+    /// Queues the chunk, it is written in the background while the body is being polled.
+    fn write(&mut self, chunk: Bytes, cx: &mut Context<'_>) -> io::Result<()> {
+        let file = self.file.get_or_insert_with(|| SpillFile::new(&self.config.dir));
+        file.len += chunk.len() as u64;
+        file.queued.push(chunk);
+        file.poll_write_queued(cx)
+    }
+}
+
+impl SpillFile {
+    fn new(dir: &Path) -> SpillFile {
+        let name = format!(
+            "kube-forwarder-{}-{}.body",
+            std::process::id(),
+            SPILL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        SpillFile { path: dir.join(name), len: 0, queued: Vec::new(), file: None, operation: None, broken: false }
+    }
+
+    /// Starts writing queued chunks when no other operation is running, doesn't wait for it.
+    fn poll_write_queued(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        loop {
+            match self.poll_operation(cx) {
+                Poll::Pending => return Ok(()),
+                Poll::Ready(result) => { result?; }
+            }
+            if self.queued.is_empty() {
+                return Ok(());
+            }
+            self.start_write()?;
+        }
+    }
+
+    /// Reads the chunk starting at `offset`, after everything queued has been written.
+    fn poll_read(&mut self, cx: &mut Context<'_>, offset: u64) -> Poll<io::Result<Bytes>> {
+        loop {
+            // a read started by a clone which was dropped meanwhile may be from another offset
+            if let Some(chunk) = futures::ready!(self.poll_operation(cx))?.filter(|(read_from, _)| *read_from == offset) {
+                return Poll::Ready(Ok(chunk.1));
+            }
+
+            if self.queued.is_empty() {
+                self.start_read(offset)?;
+            } else {
+                self.start_write()?;
+            }
+        }
+    }
+
+    /// Waits for the running operation, returns the chunk (with its offset) when it was a read.
+    fn poll_operation(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<(u64, Bytes)>>> {
+        let done = match self.operation.as_mut() {
+            None => return Poll::Ready(Ok(None)),
+            Some(SpillOperation::Write(write)) => futures::ready!(Pin::new(write).poll(cx))
+                .map_err(io::Error::other)
+                .and_then(|written| written)
+                .map(|file| (file, None)),
+            Some(SpillOperation::Read(offset, read)) => {
+                let offset = *offset;
+                futures::ready!(Pin::new(read).poll(cx))
+                    .map_err(io::Error::other)
+                    .and_then(|read| read)
+                    .map(|(file, chunk)| (file, Some((offset, chunk))))
+            }
+        };
+
+        self.operation = None;
+        match done {
+            Ok((file, chunk)) => {
+                self.file = Some(file);
+                Poll::Ready(Ok(chunk))
+            }
+            Err(err) => {
+                self.broken = true;
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+
+    fn start_write(&mut self) -> io::Result<()> {
+        let file = self.take_file()?;
+        let path = self.path.clone();
+        let chunks = std::mem::take(&mut self.queued);
+        self.operation = Some(SpillOperation::Write(tokio::task::spawn_blocking(move || {
+            let file = match file {
+                Some(file) => file,
+                None => OpenOptions::new().read(true).append(true).create_new(true).open(path)?,
+            };
+            let mut writer = BufWriter::with_capacity(SPILL_WRITE_BUFFER, file);
+            for chunk in chunks {
+                writer.write_all(&chunk)?;
+            }
+            writer.into_inner().map_err(|err| err.into_error())
+        })));
+        Ok(())
+    }
+
+    fn start_read(&mut self, offset: u64) -> io::Result<()> {
+        let mut file = self.take_file()?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "nothing has been spilled yet"))?;
+        let length = (self.len - offset).min(SPILL_READ_CHUNK as u64) as usize;
+        self.operation = Some(SpillOperation::Read(offset, tokio::task::spawn_blocking(move || {
+            let mut chunk = vec![0; length];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut chunk)?;
+            Ok((file, Bytes::from(chunk)))
+        })));
+        Ok(())
+    }
+
+    /// File for the next operation, `None` when it hasn't been created yet.
+    fn take_file(&mut self) -> io::Result<Option<File>> {
+        if self.broken {
+            return Err(io::Error::other("spilled body is broken"));
+        }
+        Ok(self.file.take())
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        // running write may create the file yet, so it is removed once the operation is done
+        let path = std::mem::take(&mut self.path);
+        match (self.operation.take(), tokio::runtime::Handle::try_current()) {
+            (Some(operation), Ok(runtime)) => {
+                runtime.spawn(async move {
+                    match operation {
+                        SpillOperation::Write(write) => { let _ = write.await; }
+                        SpillOperation::Read(_, read) => { let _ = read.await; }
+                    }
+                    remove_spilled(&path);
+                });
+            }
+            _ => remove_spilled(&path),
+        }
+    }
+}
+
+fn remove_spilled(path: &Path) {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => log::error!("unable to remove spilled body {:?}: {}", path, err),
+        _ => {}
+    }
 }
 
 impl<B: Body> ReplayBody<B> {
@@ -75,6 +306,18 @@ impl<B: Body> ReplayBody<B> {
     /// If the body has a size hint with a lower bound greater than `max_bytes`, the original body
     /// is returned in the error variant.
     pub fn try_new(body: B, max_bytes: usize) -> Result<Self, B> {
+        Self::try_new_with_spill(body, max_bytes, None)
+    }
+
+    /// Like `try_new`, but only `max_memory_bytes` are buffered in memory, further data (up to
+    /// `SpillConfig::max_disk_bytes`) is written to a temporary file and replayed from there.
+    pub fn try_new_spilling(body: B, max_memory_bytes: usize, spill: SpillConfig) -> Result<Self, B> {
+        Self::try_new_with_spill(body, max_memory_bytes, Some(spill))
+    }
+
+    fn try_new_with_spill(body: B, max_memory_bytes: usize, spill: Option<SpillConfig>) -> Result<Self, B> {
+        let max_disk_bytes = spill.as_ref().map(|spill| spill.max_disk_bytes).unwrap_or(0);
+        let max_bytes = max_memory_bytes.saturating_add(max_disk_bytes);
         let orig_size_hint = body.size_hint();
         if orig_size_hint.lower() > max_bytes as u64 {
             return Err(body);
@@ -91,46 +334,43 @@ impl<B: Body> ReplayBody<B> {
                 trailers: None,
                 rest: Some(body),
                 is_completed: false,
-                max_bytes: max_bytes + 1,
+                max_bytes: max_bytes.saturating_add(1),
+                spill: spill.map(|config| Spill { config, memory_limit: max_memory_bytes, file: None }),
             }),
             // The initial `ReplayBody` has nothing to replay
             replay_body: false,
             replay_trailers: false,
+            replay_spill: false,
+            spill_offset: 0,
         })
     }
 
     /// Mutably borrows the body state if this clone currently owns it,
     /// or else tries to acquire it from the shared state.
     ///
-    /// # Panics
-    ///
-    /// This panics if another clone has currently acquired the state, based on
-    /// the assumption that a retry body will not be polled until the previous
-    /// request has been dropped.
+    /// Returns `None` if another clone holds the state. A request which was given up on (timed
+    /// out, lost the hedge race, or was answered before the whole body was read) may still be
+    /// sending it from the connection task.
     fn acquire_state<'a>(
         state: &'a mut Option<BodyState<B>>,
         shared: &Mutex<Option<BodyState<B>>>,
-    ) -> &'a mut BodyState<B> {
-        state.get_or_insert_with(|| shared.lock().take().expect("missing body state"))
+    ) -> Option<&'a mut BodyState<B>> {
+        if state.is_none() {
+            *state = shared.lock().take();
+        }
+        state.as_mut()
     }
 
-    /// Returns `true` if the body previously exceeded the configured maximum
-    /// length limit.
+    /// Returns `true` if a clone of this body can be sent again.
     ///
-    /// If this is true, the body is now empty, and the request should *not* be
-    /// retried with this body.
-    pub fn is_capped(&self) -> bool {
-        self.state
-            .as_ref()
-            .map(BodyState::is_capped)
-            .unwrap_or_else(|| {
-                self.shared
-                    .body
-                    .lock()
-                    .as_ref()
-                    .expect("if our `state` was `None`, the shared state must be `Some`")
-                    .is_capped()
-            })
+    /// It can't when the body previously exceeded the configured maximum length
+    /// limit (the body is now empty), or while another clone still holds the
+    /// state because the previous request is still sending it.
+    pub fn is_replayable(&self) -> bool {
+        match self.state.as_ref() {
+            Some(state) => !state.is_capped(),
+            None => self.shared.body.lock().as_ref().is_some_and(|state| !state.is_capped()),
+        }
     }
 }
 
@@ -147,7 +387,10 @@ where
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
         let this = self.get_mut();
-        let state = Self::acquire_state(&mut this.state, &this.shared.body);
+        let state = match Self::acquire_state(&mut this.state, &this.shared.body) {
+            Some(state) => state,
+            None => return Poll::Ready(Some(Err(InUse.into()))),
+        };
         // Move these out to avoid mutable borrow issues in the `map` closure
         // when polling the inner body.
 
@@ -165,6 +408,23 @@ where
             }
         }
 
+        // Then the data which didn't fit in memory, chunk by chunk.
+        if this.replay_spill {
+            if let Some(file) = state.spill.as_mut().and_then(|spill| spill.file.as_mut()) {
+                if this.spill_offset < file.len {
+                    let chunk = match futures::ready!(file.poll_read(cx, this.spill_offset)) {
+                        Ok(chunk) => chunk,
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    };
+                    this.spill_offset += chunk.len() as u64;
+                    let mut replayed = BufList::default();
+                    replayed.push_chunk(chunk);
+                    return Poll::Ready(Some(Ok(Data::Replay(replayed))));
+                }
+            }
+            this.replay_spill = false;
+        }
+
         // If the inner body has previously ended, don't poll it again.
         //
         // NOTE(eliza): we would expect the inner body to just happily return
@@ -203,10 +463,16 @@ where
         let chunk = if state.is_capped() {
             // If there's data in the buffer, discard it now, since we won't
             // allow any clones to have a complete body.
-            if state.buf.has_remaining() {
-                state.buf = Default::default();
-            }
+            state.discard();
             data.copy_to_bytes(length)
+        } else if state.should_spill(length) {
+            let chunk = data.copy_to_bytes(length);
+            let written = state.spill.as_mut().map(|spill| spill.write(chunk.clone(), cx));
+            if let Some(Err(e)) = written {
+                log::error!("unable to spill body to disk, it won't be replayed: {}", e);
+                state.discard();
+            }
+            chunk
         } else {
             // Buffer and return the bytes.
             state.buf.push_chunk(data)
@@ -220,8 +486,10 @@ where
         cx: &mut Context<'_>,
     ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
         let this = self.get_mut();
-        let state = Self::acquire_state(&mut this.state, &this.shared.body);
-
+        let state = match Self::acquire_state(&mut this.state, &this.shared.body) {
+            Some(state) => state,
+            None => return Poll::Ready(Err(InUse.into())),
+        };
 
         if this.replay_trailers {
             this.replay_trailers = false;
@@ -261,7 +529,7 @@ where
 
         // if this body has data or trailers remaining to play back, it
         // is not EOS
-        !self.replay_body && !self.replay_trailers
+        !self.replay_body && !self.replay_trailers && !self.replay_spill
             // if we have replayed everything, the initial body may
             // still have data remaining, so ask it
             && is_inner_eos
@@ -277,7 +545,7 @@ where
 
         // Otherwise, if we're holding the state but have dropped the inner
         // body, the entire body is buffered so we know the exact size hint.
-        let buffered = state.buf.remaining() as u64;
+        let buffered = state.buf.remaining() as u64 + state.spilled_len();
         let rest_hint = match state.rest.as_ref() {
             Some(rest) => rest.size_hint(),
             None => return SizeHint::with_exact(buffered),
@@ -434,6 +702,8 @@ impl<B> Clone for ReplayBody<B> {
             // reading any additional data from the initial body.
             replay_body: true,
             replay_trailers: true,
+            replay_spill: true,
+            spill_offset: 0,
         }
     }
 }
@@ -445,4 +715,126 @@ impl<B> Drop for ReplayBody<B> {
             *self.shared.body.lock() = Some(state);
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    fn body(chunks: &[&'static str]) -> hyper::Body {
+        let chunks: Vec<Result<Bytes, io::Error>> = chunks.iter().map(|chunk| Ok(Bytes::from(*chunk))).collect();
+        hyper::Body::wrap_stream(stream::iter(chunks))
+    }
+
+    /// Empty directory of its own for every test, so spilled files can be counted.
+    fn spill_config(test: &str, max_disk_bytes: usize) -> SpillConfig {
+        let dir = std::env::temp_dir().join(format!("kube-forwarder-test-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        SpillConfig { dir, max_disk_bytes }
+    }
+
+    fn spilled_files(config: &SpillConfig) -> usize {
+        std::fs::read_dir(&config.dir).unwrap().count()
+    }
+
+    /// Removal waits for a write which may still be running.
+    async fn wait_for_removal(config: &SpillConfig) {
+        for _ in 0..100 {
+            if spilled_files(config) == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("spilled body was not removed");
+    }
+
+    async fn read_all(body: &mut ReplayBody<hyper::Body>) -> Result<String, Error> {
+        let mut read = BytesMut::new();
+        while let Some(mut data) = body.data().await.transpose()? {
+            let length = data.remaining();
+            read.put(data.copy_to_bytes(length));
+        }
+        Ok(String::from_utf8(read.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn replays_memory_followed_by_spill() {
+        let config = spill_config("replay", 1024);
+        let mut initial = ReplayBody::try_new_spilling(body(&["abc", "def", "ghi"]), 4, config.clone()).unwrap();
+        let mut replay = initial.clone();
+
+        assert_eq!(read_all(&mut initial).await.unwrap(), "abcdefghi");
+        drop(initial);
+
+        assert!(replay.is_replayable());
+        assert_eq!(read_all(&mut replay).await.unwrap(), "abcdefghi");
+        assert_eq!(spilled_files(&config), 1);
+
+        let mut second_replay = replay.clone();
+        drop(replay);
+        assert_eq!(read_all(&mut second_replay).await.unwrap(), "abcdefghi");
+
+        drop(second_replay);
+        std::fs::remove_dir(&config.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn body_over_disk_cap_is_not_replayed() {
+        let config = spill_config("cap", 5);
+        let mut initial = ReplayBody::try_new_spilling(body(&["abc", "def", "ghi", "jkl"]), 4, config.clone()).unwrap();
+        let mut replay = initial.clone();
+
+        // the clone which reached the cap still gets the whole body
+        assert_eq!(read_all(&mut initial).await.unwrap(), "abcdefghijkl");
+        drop(initial);
+
+        assert!(!replay.is_replayable());
+        assert!(read_all(&mut replay).await.unwrap_err().is::<Capped>());
+        wait_for_removal(&config).await;
+
+        drop(replay);
+        std::fs::remove_dir(&config.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn body_held_by_previous_request_is_not_replayed() {
+        let mut initial = ReplayBody::try_new(body(&["abc", "def"]), 64).unwrap();
+        let replay = initial.clone();
+
+        // the first request was given up on while its connection task was still sending the body
+        let mut sent = BytesMut::new();
+        let mut chunk = initial.data().await.unwrap().unwrap();
+        sent.put(chunk.copy_to_bytes(chunk.remaining()));
+        assert_eq!(&sent[..], b"abc");
+        assert!(!replay.is_replayable());
+
+        // a retry started anyway fails instead of taking the state away
+        let mut retry = replay.clone();
+        assert!(read_all(&mut retry).await.unwrap_err().is::<InUse>());
+        drop(retry);
+
+        drop(initial);
+        assert!(replay.is_replayable());
+        let mut retry = replay.clone();
+        assert_eq!(read_all(&mut retry).await.unwrap(), "abcdef");
+    }
+
+    #[tokio::test]
+    async fn spill_file_is_removed_with_last_clone() {
+        let config = spill_config("cleanup", 1024);
+        let mut initial = ReplayBody::try_new_spilling(body(&["abc", "def", "ghi"]), 4, config.clone()).unwrap();
+        let mut replay = initial.clone();
+        let other_clone = replay.clone();
+
+        read_all(&mut initial).await.unwrap();
+        drop(initial);
+        read_all(&mut replay).await.unwrap();
+        drop(replay);
+        assert_eq!(spilled_files(&config), 1);
+
+        drop(other_clone);
+        wait_for_removal(&config).await;
+        std::fs::remove_dir(&config.dir).unwrap();
+    }
+}
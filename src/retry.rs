@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::{Method, StatusCode};
+use parking_lot::Mutex;
+use rand::Rng;
+use tower::retry::budget::Budget;
+
+use crate::target_host::TargetHost;
+
+/// Deposits older than that are not taken into account by retry budgets.
+const BUDGET_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    /// also retry methods which are not idempotent (POST, PATCH...) after they were sent
+    pub retry_non_idempotent: bool,
+    /// responses with these statuses are retried as if forwarding failed
+    pub retry_statuses: Vec<StatusCode>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// no retry is started after that much time since the request was received
+    pub deadline: Duration,
+    /// retries allowed per original request to the target, e.g. 0.2 means 20% more requests
+    pub budget_ratio: f32,
+    /// retries per second allowed for the target regardless of the ratio
+    pub budget_min_per_sec: u32,
+}
+
+impl RetryPolicy {
+    /// Can the request be repeated after it (possibly) reached the pod.
+    pub fn is_retryable_method(&self, method: &Method) -> bool {
+        self.retry_non_idempotent || is_idempotent(method)
+    }
+
+    pub fn is_retryable_status(&self, status: StatusCode) -> bool {
+        self.retry_statuses.contains(&status)
+    }
+
+    /// Exponential backoff with full jitter, `retry` starts from 1.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31) as u32;
+        let ceiling = self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.max_backoff);
+
+        let ceiling_ms = ceiling.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling_ms))
+    }
+}
+
+/// Retry budgets (linkerd style), one per target. Every original request deposits `budget_ratio`
+/// of a retry, and every retry withdraws one, so when a pod goes down retries can't multiply the load.
+pub struct RetryBudgets {
+    ratio: f32,
+    min_per_sec: u32,
+    budgets: Mutex<HashMap<TargetHost, Arc<Budget>>>,
+}
+
+impl RetryBudgets {
+    pub fn new(policy: &RetryPolicy) -> RetryBudgets {
+        RetryBudgets {
+            ratio: policy.budget_ratio,
+            min_per_sec: policy.budget_min_per_sec,
+            budgets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, target: &TargetHost) -> Arc<Budget> {
+        self.budgets.lock()
+            .entry(target.clone())
+            .or_insert_with(|| Arc::new(Budget::new(BUDGET_TTL, self.min_per_sec, self.ratio)))
+            .clone()
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE)
+}
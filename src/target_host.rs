@@ -0,0 +1,156 @@
+use thiserror::Error;
+
+pub const DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+
+#[derive(Debug, Error)]
+#[error("incorrect format of the received host {0}")]
+pub struct InvalidHost(pub String);
+
+/// Application (`Service`) addressed by the Host header.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TargetHost {
+    pub application_name: String,
+    pub namespace: String,
+    pub port: Option<u16>,
+}
+
+impl TargetHost {
+    /// Parses host the same way cluster DNS names are resolved inside the cluster, accepted forms are
+    /// `app` (resolved in `default_namespace`), `app.namespace`, `app.namespace.svc`
+    /// and `app.namespace.svc.<cluster_domain>`, each optionally followed by `:port`.
+    pub fn parse(host: &str, cluster_domain: &str, default_namespace: &str) -> Result<TargetHost, InvalidHost> {
+        let HostLabels { mut labels, port, .. } = HostLabels::split(host, cluster_domain)?;
+        let namespace = match labels.len() {
+            1 => String::from(default_namespace),
+            _ => labels.remove(1),
+        };
+        Ok(TargetHost {
+            application_name: labels.remove(0),
+            namespace,
+            port,
+        })
+    }
+
+    /// Whether the host of a proxy request names a service - `app`, `app.namespace.svc` or
+    /// `app.namespace.svc.<cluster_domain>`. `app.namespace` is not accepted here,
+    /// it can't be told apart from names outside the cluster like `example.com`.
+    pub fn is_cluster_host(host: &str, cluster_domain: &str) -> bool {
+        match HostLabels::split(host, cluster_domain) {
+            Ok(HostLabels { labels, svc, .. }) => match labels.as_slice() {
+                [name] => name != "localhost" && !name.starts_with('['),
+                _ => svc,
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// Host split the way cluster DNS names are resolved: the application name (and namespace)
+/// without the `svc[.<cluster_domain>]` suffix, whether the suffix was there, and the port.
+struct HostLabels {
+    labels: Vec<String>,
+    svc: bool,
+    port: Option<u16>,
+}
+
+impl HostLabels {
+    fn split(host: &str, cluster_domain: &str) -> Result<HostLabels, InvalidHost> {
+        let invalid = || InvalidHost(String::from(host));
+
+        let (name, port) = match host.rsplit_once(':') {
+            Some((name, port)) => (name, Some(port.parse::<u16>().map_err(|_| invalid())?)),
+            None => (host, None),
+        };
+
+        // fully qualified names may end with the root dot
+        let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+        let mut labels: Vec<String> = name.split('.').map(String::from).collect();
+        if labels.iter().any(|label| label.is_empty()) {
+            return Err(invalid());
+        }
+
+        let cluster_domain = cluster_domain.trim_matches('.').to_ascii_lowercase();
+        let svc = match labels.as_slice() {
+            [_, _, svc, domain @ ..] => svc == "svc" && (domain.is_empty() || domain.join(".") == cluster_domain),
+            _ => false,
+        };
+        if labels.len() > 2 && !svc {
+            return Err(invalid());
+        }
+        labels.truncate(2);
+
+        Ok(HostLabels { labels, svc, port })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(host: &str) -> Result<TargetHost, InvalidHost> {
+        TargetHost::parse(host, DEFAULT_CLUSTER_DOMAIN, "default")
+    }
+
+    fn target(application_name: &str, namespace: &str, port: Option<u16>) -> TargetHost {
+        TargetHost {
+            application_name: String::from(application_name),
+            namespace: String::from(namespace),
+            port,
+        }
+    }
+
+    #[test]
+    fn single_label_goes_to_default_namespace() {
+        assert_eq!(parse("app").unwrap(), target("app", "default", None));
+    }
+
+    #[test]
+    fn accepts_cluster_dns_forms() {
+        assert_eq!(parse("app.ns").unwrap(), target("app", "ns", None));
+        assert_eq!(parse("app.ns.svc").unwrap(), target("app", "ns", None));
+        assert_eq!(parse("app.ns.svc.cluster.local").unwrap(), target("app", "ns", None));
+        assert_eq!(parse("app.ns.svc.cluster.local.").unwrap(), target("app", "ns", None));
+    }
+
+    #[test]
+    fn accepts_port() {
+        assert_eq!(parse("app:8080").unwrap(), target("app", "default", Some(8080)));
+        assert_eq!(parse("app.ns.svc.cluster.local:80").unwrap(), target("app", "ns", Some(80)));
+        assert_eq!(parse("app.ns.svc.cluster.local.:80").unwrap(), target("app", "ns", Some(80)));
+    }
+
+    #[test]
+    fn ignores_case() {
+        assert_eq!(parse("App.NS.Svc.Cluster.Local").unwrap(), target("app", "ns", None));
+        assert!(TargetHost::parse("app.ns.svc.Corp.Example", "corp.example.", "default").is_ok());
+    }
+
+    #[test]
+    fn accepts_custom_cluster_domain() {
+        let parsed = TargetHost::parse("app.ns.svc.corp.example", "corp.example", "default").unwrap();
+        assert_eq!(parsed, target("app", "ns", None));
+        assert!(TargetHost::parse("app.ns.svc.cluster.local", "corp.example", "default").is_err());
+    }
+
+    #[test]
+    fn rejects_other_forms() {
+        assert!(parse("app.ns.svc.other.domain").is_err());
+        assert!(parse("app.ns.other").is_err());
+        assert!(parse("host:notaport").is_err());
+        assert!(parse("app:99999").is_err());
+        assert!(parse("").is_err());
+        assert!(parse(".").is_err());
+        assert!(parse("app..ns").is_err());
+        assert!(parse(".app").is_err());
+    }
+
+    #[test]
+    fn cluster_hosts_of_proxy_requests() {
+        assert!(TargetHost::is_cluster_host("app", DEFAULT_CLUSTER_DOMAIN));
+        assert!(TargetHost::is_cluster_host("app.ns.svc:8080", DEFAULT_CLUSTER_DOMAIN));
+        assert!(TargetHost::is_cluster_host("app.ns.svc.cluster.local.", DEFAULT_CLUSTER_DOMAIN));
+        assert!(!TargetHost::is_cluster_host("example.com", DEFAULT_CLUSTER_DOMAIN));
+        assert!(!TargetHost::is_cluster_host("app.ns.svc.other.domain", DEFAULT_CLUSTER_DOMAIN));
+        assert!(!TargetHost::is_cluster_host("localhost:8080", DEFAULT_CLUSTER_DOMAIN));
+    }
+}
@@ -0,0 +1,58 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::forwarding_service::Forwarder;
+use crate::target_host::{InvalidHost, TargetHost};
+
+/// Local port whose connections are forwarded as they are (no HTTP) to a port of a service.
+#[derive(Debug, Clone)]
+pub struct TcpForward {
+    pub local_port: u16,
+    pub target: TargetHost,
+}
+
+impl TcpForward {
+    /// `<local port>=<host>:<port>`, e.g. `15432=postgres.db:5432`
+    pub fn parse(forward: &str, cluster_domain: &str, default_namespace: &str) -> Result<TcpForward, InvalidHost> {
+        let invalid = || InvalidHost(String::from(forward));
+        let (local_port, host) = forward.split_once('=').ok_or_else(invalid)?;
+        let local_port = local_port.parse().map_err(|_| invalid())?;
+        let target = TargetHost::parse(host, cluster_domain, default_namespace)?;
+        if target.port.is_none() {
+            return Err(invalid());
+        }
+        Ok(TcpForward { local_port, target })
+    }
+}
+
+/// Accepts connections on the local port, every one of them gets its own port-forward stream
+/// (so a pod which went away is replaced on the next connection).
+pub async fn serve(forwarder: Arc<Forwarder>, forward: TcpForward) {
+    let addr = SocketAddr::from(([127, 0, 0, 1], forward.local_port));
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("unable to listen on {} for {:?}: {}", addr, forward.target, err);
+            return;
+        }
+    };
+    log::info!("forwarding tcp connections to {} to {}.{}:{}", addr,
+               forward.target.application_name, forward.target.namespace, forward.target.port.unwrap_or_default());
+
+    loop {
+        let (client, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("unable to accept connection on {}: {}", addr, err);
+                continue;
+            }
+        };
+
+        let forwarder = forwarder.clone();
+        let target = forward.target.clone();
+        tokio::spawn(async move {
+            forwarder.forward_tcp(&target, client, peer).await;
+        });
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    /// whole request, including all retries
+    pub request: Duration,
+    /// single forwarding attempt, until the response head is received
+    pub attempt: Duration,
+    /// finding the service and its ready pods
+    pub discovery: Duration,
+    /// opening port-forward to the chosen pod
+    pub port_forward: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Request,
+    Attempt,
+    Discovery,
+    PortForward,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let phase = match self {
+            Phase::Request => "request",
+            Phase::Attempt => "forwarding attempt",
+            Phase::Discovery => "pod discovery",
+            Phase::PortForward => "port-forward setup",
+        };
+        f.write_str(phase)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{phase} timed out after {}ms", after.as_millis())]
+pub struct TimedOut {
+    pub phase: Phase,
+    pub after: Duration,
+}
+
+pub async fn with_timeout<F: Future>(phase: Phase, after: Duration, future: F) -> Result<F::Output, TimedOut> {
+    tokio::time::timeout(after, future)
+        .await
+        .map_err(|_| TimedOut { phase, after })
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::upgrade::Upgraded;
+use hyper::HeaderMap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const SPLICE_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    ClientToPod,
+    PodToClient,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::ClientToPod => f.write_str("client -> pod"),
+            Direction::PodToClient => f.write_str("pod -> client"),
+        }
+    }
+}
+
+type TapFn = dyn Fn(Direction, &[u8]) + Send + Sync;
+
+/// Sees every chunk of data going through an upgraded connection. Put into request extensions
+/// (e.g. by `LogService`) to inspect upgraded connections of that request.
+#[derive(Clone)]
+pub struct UpgradeTap(pub Arc<TapFn>);
+
+/// `Connection: upgrade` together with `Upgrade: <protocol>`, e.g. WebSocket handshake.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    let connection_upgrade = headers.get_all(CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+
+    connection_upgrade && headers.contains_key(UPGRADE)
+}
+
+/// Copies data between upgraded client and pod connections in both directions until both are done.
+/// Returns number of bytes sent to the pod and to the client.
+pub async fn splice(client: Upgraded, pod: Upgraded, tap: Option<UpgradeTap>) -> io::Result<(u64, u64)> {
+    let (client_read, client_write) = tokio::io::split(client);
+    let (pod_read, pod_write) = tokio::io::split(pod);
+
+    tokio::try_join!(
+        pump(client_read, pod_write, Direction::ClientToPod, tap.clone()),
+        pump(pod_read, client_write, Direction::PodToClient, tap),
+    )
+}
+
+async fn pump<R, W>(mut from: R, mut to: W, direction: Direction, tap: Option<UpgradeTap>) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; SPLICE_BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let read = from.read(&mut buf).await?;
+        if read == 0 {
+            // lets the other side know nothing more is coming, the opposite direction may still go on
+            to.shutdown().await?;
+            return Ok(copied);
+        }
+
+        if let Some(tap) = &tap {
+            (tap.0)(direction, &buf[..read]);
+        }
+        to.write_all(&buf[..read]).await?;
+        copied += read as u64;
+    }
+}
@@ -0,0 +1,55 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use http::HeaderMap;
+use http_body::{Body, SizeHint};
+
+use crate::reply_body::{Data, Error, ReplayBody};
+
+/// Body of the request sent to the pod. Bodies small enough to be buffered are replayable,
+/// larger ones are streamed as they come and can't be retried.
+#[derive(Debug)]
+pub enum UpstreamBody {
+    Replayable(Box<ReplayBody<hyper::Body>>),
+    Streaming(hyper::Body),
+}
+
+impl Body for UpstreamBody {
+    type Data = Data;
+    type Error = Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        match self.get_mut() {
+            UpstreamBody::Replayable(body) => Pin::new(body).poll_data(cx),
+            UpstreamBody::Streaming(body) => Pin::new(body)
+                .poll_data(cx)
+                .map(|chunk| chunk.map(|chunk| chunk.map(Data::Initial).map_err(Into::into))),
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        match self.get_mut() {
+            UpstreamBody::Replayable(body) => Pin::new(body).poll_trailers(cx),
+            UpstreamBody::Streaming(body) => Pin::new(body).poll_trailers(cx).map_err(Into::into),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            UpstreamBody::Replayable(body) => body.is_end_stream(),
+            UpstreamBody::Streaming(body) => body.is_end_stream(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            UpstreamBody::Replayable(body) => body.size_hint(),
+            UpstreamBody::Streaming(body) => body.size_hint(),
+        }
+    }
+}
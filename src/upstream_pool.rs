@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use futures::future::poll_fn;
+use futures::FutureExt;
+use hyper::client::conn::SendRequest;
+use hyper::{Request, Response};
+use parking_lot::Mutex;
+use tokio::sync::{OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+use crate::target_host::TargetHost;
+use crate::upstream_body::UpstreamBody;
+
+/// Upstream connections are shared only between requests for the same target.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub cluster: String,
+    pub namespace: String,
+    pub service: String,
+    pub port: Option<u16>,
+}
+
+impl PoolKey {
+    pub fn new(cluster: &str, target: &TargetHost) -> PoolKey {
+        PoolKey {
+            cluster: String::from(cluster),
+            namespace: target.namespace.clone(),
+            service: target.application_name.clone(),
+            port: target.port,
+        }
+    }
+}
+
+/// HTTP/1 connection opened over port-forward, together with the pod it goes to.
+pub struct PooledConnection {
+    pub sender: SendRequest<UpstreamBody>,
+    pub pod: String,
+}
+
+/// HTTP/2 connection opened over port-forward, carries any number of concurrent requests.
+/// hyper's `SendRequest` can't be cloned, so it is shared behind a lock which is held only
+/// while the request is handed to the connection, not while its response is awaited.
+#[derive(Clone)]
+pub struct MultiplexedConnection {
+    sender: Arc<tokio::sync::Mutex<SendRequest<UpstreamBody>>>,
+    pub pod: String,
+}
+
+impl MultiplexedConnection {
+    pub fn new(sender: SendRequest<UpstreamBody>, pod: String) -> MultiplexedConnection {
+        MultiplexedConnection { sender: Arc::new(tokio::sync::Mutex::new(sender)), pod }
+    }
+
+    pub async fn send_request(&self, req: Request<UpstreamBody>) -> Result<Response<hyper::Body>, hyper::Error> {
+        let response = {
+            let mut sender = self.sender.lock().await;
+            poll_fn(|cx| sender.poll_ready(cx)).await?;
+            sender.send_request(req)
+        };
+        response.await
+    }
+
+    fn is_closed(&self) -> bool {
+        // locked only while a request is being handed over, so the connection is alive
+        match self.sender.try_lock() {
+            // not ready is not closed - the connection task may not have taken the previous request
+            // yet, or the pod's MAX_CONCURRENT_STREAMS are in use, only an error means it is gone
+            Ok(mut sender) => matches!(poll_fn(|cx| sender.poll_ready(cx)).now_or_never(), Some(Err(_))),
+            Err(_) => false,
+        }
+    }
+}
+
+struct Idle {
+    connection: PooledConnection,
+    since: Instant,
+}
+
+struct Shared {
+    connection: MultiplexedConnection,
+    last_used: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// idle connections older than that are closed
+    pub idle_timeout: Duration,
+    /// maximum number of idle connections kept (for all targets together)
+    pub max_idle: usize,
+    /// maximum number of connections used at the same time for a single target
+    pub max_per_target: usize,
+}
+
+/// Process-wide pool of upstream connections. HTTP/1 connection is checked out for a request and
+/// goes back to the pool when it is ready for the next one (i.e. previous response has been
+/// fully read), so it is never used by two requests at once. HTTP/2 connections stay in the pool
+/// and are used by all requests to the target at the same time.
+pub struct UpstreamPool {
+    config: PoolConfig,
+    idle: Mutex<HashMap<PoolKey, VecDeque<Idle>>>,
+    in_use: Mutex<HashMap<PoolKey, Arc<Semaphore>>>,
+    shared: Mutex<HashMap<PoolKey, Vec<Shared>>>,
+    /// targets whose connections turned out to be HTTP/1, they are opened concurrently
+    http1_targets: Mutex<HashSet<PoolKey>>,
+    /// other targets open connections one at a time, so concurrent requests end up on the first HTTP/2 one
+    opening: Mutex<HashMap<PoolKey, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl UpstreamPool {
+    pub fn new(config: PoolConfig) -> Arc<UpstreamPool> {
+        let pool = Arc::new(UpstreamPool {
+            config,
+            idle: Mutex::new(HashMap::new()),
+            in_use: Mutex::new(HashMap::new()),
+            shared: Mutex::new(HashMap::new()),
+            http1_targets: Mutex::new(HashSet::new()),
+            opening: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(reap_idle(Arc::downgrade(&pool)));
+        pool
+    }
+
+    /// Most recently opened HTTP/2 connection to the target going to a `usable` pod, it stays in the pool.
+    pub fn multiplexed(&self, key: &PoolKey, usable: impl Fn(&str) -> bool) -> Option<MultiplexedConnection> {
+        let mut shared = self.shared.lock();
+        let connections = shared.get_mut(key)?;
+        connections.retain(|candidate| !candidate.connection.is_closed());
+        let found = connections.iter_mut()
+            .rev()
+            .find(|candidate| usable(&candidate.connection.pod))
+            .map(|candidate| {
+                candidate.last_used = Instant::now();
+                candidate.connection.clone()
+            });
+
+        if connections.is_empty() {
+            shared.remove(key);
+        }
+        found
+    }
+
+    /// Makes newly opened HTTP/2 connection available to other requests to the target.
+    pub fn share(&self, key: PoolKey, connection: MultiplexedConnection) {
+        self.shared.lock()
+            .entry(key)
+            .or_default()
+            .push(Shared { connection, last_used: Instant::now() });
+    }
+
+    /// Waits until no other request opens a connection to the target, unless the target is known
+    /// to speak HTTP/1 (those connections can't be shared, so there is nothing to wait for).
+    pub async fn opening(&self, key: &PoolKey) -> Option<OwnedMutexGuard<()>> {
+        if self.http1_targets.lock().contains(key) {
+            return None;
+        }
+
+        let lock = self.opening.lock()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = lock.lock_owned().await;
+        (!self.http1_targets.lock().contains(key)).then_some(guard)
+    }
+
+    pub fn mark_http1(&self, key: &PoolKey) {
+        if self.http1_targets.lock().insert(key.clone()) {
+            self.opening.lock().remove(key);
+        }
+    }
+
+    /// Waits until another connection to the target can be used. Returned permit should be
+    /// given back together with the connection in `checkin`.
+    pub async fn acquire(&self, key: &PoolKey) -> OwnedSemaphorePermit {
+        let semaphore = self.in_use.lock()
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.config.max_per_target.max(1))))
+            .clone();
+
+        semaphore.acquire_owned().await.expect("pool semaphores are never closed")
+    }
+
+    /// Takes most recently used idle connection for the target which is ready to send a request,
+    /// and goes to a `usable` pod. Connections which died in the meantime are dropped, so caller
+    /// opens a new one instead.
+    pub fn checkout(&self, key: &PoolKey, usable: impl Fn(&str) -> bool) -> Option<PooledConnection> {
+        let mut idle = self.idle.lock();
+        let connections = idle.get_mut(key)?;
+        connections.retain_mut(|candidate| !self.is_expired(candidate));
+        let found = connections.iter()
+            .rposition(|candidate| usable(&candidate.connection.pod))
+            .and_then(|position| connections.remove(position))
+            .map(|candidate| candidate.connection);
+
+        if connections.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    /// Returns connection to the pool as soon as it is ready for the next request, connections which
+    /// failed meanwhile are dropped. Target's permit is released at the same time.
+    pub fn checkin(self: &Arc<Self>, key: PoolKey, mut connection: PooledConnection, permit: OwnedSemaphorePermit) {
+        let pool = Arc::downgrade(self);
+        let idle_timeout = self.config.idle_timeout;
+        tokio::spawn(async move {
+            let ready = timeout(idle_timeout, poll_fn(|cx| connection.sender.poll_ready(cx))).await;
+            drop(permit);
+            match (ready, pool.upgrade()) {
+                (Ok(Ok(())), Some(pool)) => pool.push_idle(key, connection),
+                (Ok(Err(err)), _) => log::info!("connection to pod {} died: {}", connection.pod, err),
+                _ => {}
+            }
+        });
+    }
+
+    /// When the pool is full, the oldest idle connection is closed.
+    fn push_idle(&self, key: PoolKey, connection: PooledConnection) {
+        if self.config.max_idle == 0 {
+            return;
+        }
+
+        let mut idle = self.idle.lock();
+        let idle_count: usize = idle.values().map(VecDeque::len).sum();
+        if idle_count >= self.config.max_idle {
+            evict_oldest(&mut idle);
+        }
+
+        idle.entry(key)
+            .or_default()
+            .push_back(Idle { connection, since: Instant::now() });
+    }
+
+    fn is_expired(&self, idle: &mut Idle) -> bool {
+        !is_ready(&mut idle.connection.sender) || idle.since.elapsed() >= self.config.idle_timeout
+    }
+
+    fn remove_expired(&self) {
+        let mut idle = self.idle.lock();
+        for connections in idle.values_mut() {
+            connections.retain_mut(|candidate| !self.is_expired(candidate));
+        }
+        idle.retain(|_, connections| !connections.is_empty());
+        drop(idle);
+
+        // requests still using a dropped HTTP/2 connection keep it open until they are done
+        let mut shared = self.shared.lock();
+        for connections in shared.values_mut() {
+            connections.retain(|candidate| !candidate.connection.is_closed() && candidate.last_used.elapsed() < self.config.idle_timeout);
+        }
+        shared.retain(|_, connections| !connections.is_empty());
+    }
+}
+
+/// Idle connection should be always ready, otherwise connection task has finished
+/// (pod went away, port-forward broke...) and sender can't be used anymore.
+fn is_ready(sender: &mut SendRequest<UpstreamBody>) -> bool {
+    let ready = poll_fn(|cx| sender.poll_ready(cx)).now_or_never();
+    matches!(ready, Some(Ok(())))
+}
+
+fn evict_oldest(idle: &mut HashMap<PoolKey, VecDeque<Idle>>) {
+    // connections are pushed back, so the oldest one for every key is at the front
+    let oldest = idle.iter()
+        .filter_map(|(key, connections)| connections.front().map(|oldest| (key, oldest.since)))
+        .min_by_key(|(_, since)| *since)
+        .map(|(key, _)| key.clone());
+
+    if let Some(key) = oldest {
+        if let Some(connections) = idle.get_mut(&key) {
+            connections.pop_front();
+            if connections.is_empty() {
+                idle.remove(&key);
+            }
+        }
+    }
+}
+
+/// Periodically drops expired idle connections (which closes their port-forwards), until the pool is gone.
+async fn reap_idle(pool: Weak<UpstreamPool>) {
+    let period = match pool.upgrade() {
+        Some(pool) => pool.config.idle_timeout.max(Duration::from_secs(1)),
+        None => return,
+    };
+
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match pool.upgrade() {
+            Some(pool) => pool.remove_expired(),
+            None => return,
+        }
+    }
+}
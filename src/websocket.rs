@@ -0,0 +1,235 @@
+use std::sync::Arc;
+use parking_lot::Mutex;
+
+use crate::upgrade::{Direction, UpgradeTap};
+
+/// Longer payloads are cut in the log.
+const MAX_LOGGED_PAYLOAD: usize = 256;
+
+/// WebSocket frame header (RFC 6455, section 5.2) with the start of the unmasked payload.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    length: u64,
+    /// at most `MAX_LOGGED_PAYLOAD` bytes, the rest is skipped
+    payload: Vec<u8>,
+}
+
+/// Frame whose payload is being read.
+struct Reading {
+    frame: Frame,
+    mask: Option<[u8; 4]>,
+    read: u64,
+}
+
+impl Reading {
+    fn start(header: &[u8]) -> Reading {
+        let (length, offset) = match header[1] & 0x7f {
+            126 => (u16::from_be_bytes([header[2], header[3]]) as u64, 4),
+            127 => (u64::from_be_bytes(header[2..10].try_into().unwrap()), 10),
+            length => (length as u64, 2),
+        };
+        let masked = header[1] & 0x80 != 0;
+        Reading {
+            frame: Frame { fin: header[0] & 0x80 != 0, opcode: header[0] & 0x0f, length, payload: Vec::new() },
+            mask: masked.then(|| header[offset..offset + 4].try_into().unwrap()),
+            read: 0,
+        }
+    }
+
+    /// Consumes the part of `data` belonging to the payload, returns the rest.
+    fn read_payload<'a>(&mut self, data: &'a [u8]) -> &'a [u8] {
+        let left = self.frame.length - self.read;
+        let take = usize::try_from(left).map_or(data.len(), |left| left.min(data.len()));
+        let logged = take.min(MAX_LOGGED_PAYLOAD - self.frame.payload.len());
+        for (i, byte) in data[..logged].iter().enumerate() {
+            let key = self.mask.map_or(0, |mask| mask[((self.read + i as u64) % 4) as usize]);
+            self.frame.payload.push(byte ^ key);
+        }
+        self.read += take as u64;
+        &data[take..]
+    }
+
+    fn is_done(&self) -> bool {
+        self.read == self.frame.length
+    }
+}
+
+/// Splits the byte stream of one direction of WebSocket connection into frames. Only headers and
+/// the logged start of payloads are kept, so large frames are not buffered.
+#[derive(Default)]
+struct FrameReader {
+    header: Vec<u8>,
+    reading: Option<Reading>,
+}
+
+impl FrameReader {
+    fn push(&mut self, mut data: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        loop {
+            match self.reading.as_mut() {
+                Some(reading) => {
+                    data = reading.read_payload(data);
+                    if !reading.is_done() {
+                        return frames;
+                    }
+                    frames.push(self.reading.take().unwrap().frame);
+                }
+                None => {
+                    let wanted = header_length(&self.header);
+                    if self.header.len() < wanted {
+                        if data.is_empty() {
+                            return frames;
+                        }
+                        let take = (wanted - self.header.len()).min(data.len());
+                        self.header.extend_from_slice(&data[..take]);
+                        data = &data[take..];
+                    } else {
+                        self.reading = Some(Reading::start(&self.header));
+                        self.header.clear();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Length of the frame header, as far as it can be told from its first bytes.
+fn header_length(header: &[u8]) -> usize {
+    if header.len() < 2 {
+        return 2;
+    }
+    let extended = match header[1] & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    2 + extended + mask
+}
+
+/// Tap logging WebSocket frames going through the upgraded connection at debug level.
+pub fn frame_logger(host: String) -> UpgradeTap {
+    let readers: Arc<Mutex<(FrameReader, FrameReader)>> = Arc::default();
+    UpgradeTap(Arc::new(move |direction, data| {
+        let mut readers = readers.lock();
+        let reader = match direction {
+            Direction::ClientToPod => &mut readers.0,
+            Direction::PodToClient => &mut readers.1,
+        };
+
+        for frame in reader.push(data) {
+            log_frame(&host, direction, &frame);
+        }
+    }))
+}
+
+fn log_frame(host: &str, direction: Direction, frame: &Frame) {
+    let kind = match frame.opcode {
+        0x0 => "continuation",
+        0x1 => "text",
+        0x2 => "binary",
+        0x8 => "close",
+        0x9 => "ping",
+        0xa => "pong",
+        _ => "unknown",
+    };
+    let payload = match frame.opcode {
+        0x1 | 0x0 => String::from_utf8_lossy(&frame.payload).into_owned(),
+        _ => format!("{:02x?}", frame.payload),
+    };
+
+    log::debug!("[{}] websocket {} {} frame ({} bytes{}): {}",
+                host, direction, kind, frame.length, if frame.fin { "" } else { ", not final" }, payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(fin: bool, opcode: u8, length: u64, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut header = vec![if fin { 0x80 } else { 0 } | opcode];
+        let masked = if mask.is_some() { 0x80 } else { 0 };
+        match length {
+            0..=125 => header.push(masked | length as u8),
+            126..=0xffff => {
+                header.push(masked | 126);
+                header.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            _ => {
+                header.push(masked | 127);
+                header.extend_from_slice(&length.to_be_bytes());
+            }
+        }
+        header.extend(mask.iter().flatten());
+        header
+    }
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut frame = header(fin, opcode, payload.len() as u64, mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask.map_or(0, |mask| mask[i % 4])));
+        frame
+    }
+
+    #[test]
+    fn unmasks_payload() {
+        let frames = FrameReader::default().push(&frame(true, 0x1, b"hello websocket", Some([1, 2, 3, 4])));
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].fin);
+        assert_eq!(frames[0].opcode, 0x1);
+        assert_eq!(frames[0].length, 15);
+        assert_eq!(frames[0].payload, b"hello websocket");
+    }
+
+    #[test]
+    fn reads_16_bit_length() {
+        let payload = vec![b'a'; 300];
+        let frames = FrameReader::default().push(&frame(true, 0x2, &payload, Some([9, 8, 7, 6])));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].length, 300);
+        assert_eq!(frames[0].payload, &payload[..MAX_LOGGED_PAYLOAD]);
+    }
+
+    #[test]
+    fn reads_64_bit_length_without_buffering_payload() {
+        let length = 100_000;
+        let mut reader = FrameReader::default();
+        assert!(reader.push(&header(true, 0x2, length, None)).is_empty());
+        for _ in 0..length / 1000 - 1 {
+            assert!(reader.push(&[7; 1000]).is_empty());
+        }
+        assert!(reader.reading.as_ref().unwrap().frame.payload.len() <= MAX_LOGGED_PAYLOAD);
+
+        let frames = reader.push(&[7; 1000]);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].length, length);
+        assert_eq!(frames[0].payload, vec![7; MAX_LOGGED_PAYLOAD]);
+    }
+
+    #[test]
+    fn bogus_length_does_not_overflow() {
+        let mut reader = FrameReader::default();
+        assert!(reader.push(&header(true, 0x2, u64::MAX, Some([1, 1, 1, 1]))).is_empty());
+        assert!(reader.push(&[0; 4096]).is_empty());
+    }
+
+    #[test]
+    fn reads_fragmented_message_split_across_pushes() {
+        let mut stream = frame(false, 0x1, b"hel", Some([5, 6, 7, 8]));
+        stream.extend(frame(false, 0x0, b"lo ", Some([1, 2, 3, 4])));
+        stream.extend(frame(true, 0x0, b"there", Some([4, 3, 2, 1])));
+
+        let mut reader = FrameReader::default();
+        let frames: Vec<Frame> = stream.chunks(3).flat_map(|chunk| reader.push(chunk)).collect();
+        let fragments: Vec<(bool, u8, &[u8])> = frames.iter().map(|frame| (frame.fin, frame.opcode, &frame.payload[..])).collect();
+        assert_eq!(fragments, vec![(false, 0x1, &b"hel"[..]), (false, 0x0, &b"lo "[..]), (true, 0x0, &b"there"[..])]);
+    }
+
+    #[test]
+    fn reads_empty_frames() {
+        let mut stream = frame(true, 0x9, b"", None);
+        stream.extend(frame(true, 0xa, b"", Some([1, 2, 3, 4])));
+        let frames = FrameReader::default().push(&stream);
+        assert_eq!(frames.iter().map(|frame| frame.opcode).collect::<Vec<_>>(), vec![0x9, 0xa]);
+    }
+}